@@ -8,10 +8,12 @@ mod inner {
     pub fn main() {
         let out_dir = env::var_os("OUT_DIR").unwrap();
 
-        let src = Path::new("src/types.rs.in");
-        let dst = Path::new(&out_dir).join("types.rs");
+        for name in &["types", "config"] {
+            let src = Path::new("src").join(format!("{}.rs.in", name));
+            let dst = Path::new(&out_dir).join(format!("{}.rs", name));
 
-        serde_codegen::expand(&src, &dst).unwrap();
+            serde_codegen::expand(&src, &dst).unwrap();
+        }
     }
 }
 
@@ -20,6 +22,16 @@ mod inner {
     pub fn main() {}
 }
 
+extern crate cc;
+
+// Wrappers catching libjpeg's fatal errors, see the file.
+fn compile_jpeg_shim() {
+    cc::Build::new()
+        .file("src/jpeg_shim.c")
+        .compile("thumbrs_jpeg_shim");
+}
+
 fn main() {
     inner::main();
+    compile_jpeg_shim();
 }
@@ -24,7 +24,10 @@ use std::io::prelude::*;
 
 use std::fs::{self, File};
 
-use std::path::Path;
+use std::cmp;
+use std::fmt::Display;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
 
 use filetime::FileTime;
 
@@ -35,16 +38,24 @@ use chrono::Local;
 
 use docopt::Docopt;
 
-use std::thread;
-use std::sync::{Arc, mpsc};
+use std::sync::{Arc, Mutex};
+
+
+use thumbrs::pool::{PoolHandle, Permit, Semaphore, ThreadPool};
 
 #[allow(dead_code)]
+#[derive(Clone)]
 struct Options {
     generate_thumbnails: bool,
     compare_by_hash: bool,
-    thumbnail_sizes: Vec<u32>,
-    thumbnail_qualities: Vec<u8>,
-    hidden_dirs: Vec<String>,
+    thumbnails: Vec<ThumbnailSpec>,
+    filter: PathFilter,
+    quarantine_dir: Option<PathBuf>,
+    cascade_ratio: Option<f64>,
+    // thumbnails up to this size are made from embedded previews if possible
+    preview_max_size: u32,
+    jobs: usize,
+    max_decodes: usize,
 }
 
 // Iterate through `iter` while it matches `prefix`; return `None` if `prefix`
@@ -90,27 +101,513 @@ fn get_mtime(metadata: &Result<fs::Metadata, std::io::Error>) -> DateTime<Local>
      .unwrap_or(Local::now())
 }
 
-fn walk_filetree(input_path: &Path, output_path: &Path, opt: &Options) {
-    walk_filetree_impl(&input_path, &input_path, &output_path, &opt, Vec::new());
+// Shared state of a tree walk.
+struct Context {
+    // for looking up profiles referenced by `.thumbrs.toml` files
+    config: Config,
+    // `--no-thumbs` cannot be undone by a `.thumbrs.toml`
+    force_metadata_only: bool,
+    pool: ThreadPool,
+    // bounds the number of full resolution images in memory
+    decode_budget: Arc<Semaphore>,
+    failures: Arc<Mutex<Vec<FileError>>>,
+    // images whose extension does not match their contents, with the
+    // detected format
+    misnamed: Mutex<Vec<(PathBuf, FileFormat)>>,
 }
 
-fn is_dir (entry: &fs::DirEntry, options: &Options) -> bool {
-    let dir_name = String::from(entry.file_name().to_str().unwrap());
-    if let Some(_) = options.hidden_dirs.iter().find(|&e| e == &dir_name)
+fn walk_filetree(input_path: &Path, output_path: &Path, opt: &Options, config: &Config) {
+    let failures = Arc::new(Mutex::new(Vec::<FileError>::new()));
+    let misnamed;
     {
-        return false;
+        let ctx = Context {
+            config: config.clone(),
+            force_metadata_only: !opt.generate_thumbnails,
+            pool: ThreadPool::new(opt.jobs),
+            decode_budget: Arc::new(Semaphore::new(opt.max_decodes)),
+            failures: failures.clone(),
+            misnamed: Mutex::new(Vec::new())
+        };
+        walk_filetree_impl(&input_path, &input_path, &output_path, &ctx, Arc::new(opt.clone()), Vec::new());
+        misnamed = ctx.misnamed.into_inner().unwrap();
+        // dropping `ctx` waits for the pool to finish all queued work
+    }
+
+    let failures = failures.lock().unwrap();
+
+    println!("");
+    if misnamed.len() > 0 {
+        println!("{} file(s) are not named like their format:", misnamed.len());
+        for &(ref path, format) in misnamed.iter() {
+            println!("  {} ({:?})", path.display(), format);
+        }
+    }
+    if failures.len() == 0 {
+        println!("All files processed successfully.");
+        return;
+    }
+
+    println!("{} file(s) failed:", failures.len());
+    for f in failures.iter() {
+        println!("  {} ({:?}): {}", f.filename, f.stage, f.message);
+        if let Some(ref err) = f.quarantine_error {
+            println!("    not quarantined: {}", err);
+        }
+    }
+    if let Some(ref dir) = opt.quarantine_dir {
+        let moved = failures.iter().filter(|f| f.quarantined_to.is_some()).count();
+        if moved > 0 {
+            println!("{} damaged file(s) were moved to {}", moved, dir.display());
+        }
+    }
+}
+
+// Moves a damaged file into the quarantine directory,
+// mirroring its path relative to the photo collection root.
+fn quarantine(in_abspath: &Path, in_relpath: &Path, quarantine_dir: &Path) -> Result<PathBuf, String> {
+    let target = quarantine_dir.join(in_relpath);
+    if let Some(parent) = target.parent() {
+        try!(fs::create_dir_all(parent)
+            .map_err(|err| format!("could not create '{}': {}", parent.display(), err)));
+    }
+    try!(fs::rename(in_abspath, &target)
+        .map_err(|err| format!("could not move to '{}': {}", target.display(), err)));
+    Ok(target)
+}
+
+fn file_failure(in_relpath: &Path, stage: ProcessingStage, err: &Display) -> FileError {
+    FileError {
+        filename: in_relpath.to_string_lossy().into_owned(),
+        stage: stage,
+        message: err.to_string(),
+        quarantined_to: None,
+        quarantine_error: None
+    }
+}
+
+// Like `file_failure`, but moves the file to quarantine if `err` means that
+// it is damaged. Files that are valid but unsupported, or that could not be
+// read at all, are left alone.
+fn image_failure(
+    in_abspath: &Path,
+    in_relpath: &Path,
+    stage: ProcessingStage,
+    err: &Error,
+    options: &Options) -> FileError
+{
+    let mut failure = file_failure(in_relpath, stage, err);
+    if err.is_damaged_input() {
+        if let Some(ref dir) = options.quarantine_dir {
+            match quarantine(in_abspath, in_relpath, dir) {
+                Ok(target) => failure.quarantined_to = Some(target.to_string_lossy().into_owned()),
+                Err(err) => failure.quarantine_error = Some(err)
+            }
+        }
     }
-    entry.metadata().unwrap().is_dir()
+    failure
+}
+
+// Aggregates the results for the images of one directory. The index and
+// error report are written once the last image referencing it is done.
+struct DirIndex {
+    json_file: PathBuf,
+    json_relpath: PathBuf,
+    errors_file: PathBuf,
+    options: Arc<Options>,
+    all_failures: Arc<Mutex<Vec<FileError>>>,
+    state: Mutex<DirState>,
+}
+
+struct DirState {
+    infos: Vec<Option<FileInfo>>,
+    failures: Vec<FileError>,
+}
+
+impl DirIndex {
+    fn finish(&self, index: usize, info: Option<FileInfo>, failures: Vec<FileError>) {
+        let mut state = self.state.lock().unwrap();
+        state.infos[index] = info;
+        state.failures.extend(failures);
+    }
+}
+
+impl Drop for DirIndex {
+    fn drop(&mut self) {
+        let state = match self.state.get_mut() {
+            Ok(state) => state,
+            Err(poisoned) => poisoned.into_inner()
+        };
+        let generation_infos = state.infos.drain(..).filter_map(|i| i).collect::<Vec<_>>();
+        let mut dir_failures = state.failures.drain(..).collect::<Vec<_>>();
+
+        if generation_infos.len() > 0 {
+            let j = serde_json::to_string_pretty(&generation_infos).unwrap();
+
+            if let Err(err) = File::create(&self.json_file).and_then(|mut f| f.write_all(j.as_bytes())) {
+                dir_failures.push(file_failure(&self.json_relpath, ProcessingStage::Index, &err));
+            }
+        }
+
+        // per-directory failure report, next to the index
+        if dir_failures.len() > 0 {
+            let j = serde_json::to_string_pretty(&dir_failures).unwrap();
+
+            if let Err(err) = File::create(&self.errors_file).and_then(|mut f| f.write_all(j.as_bytes())) {
+                warn!("could not write '{}': {}", self.errors_file.display(), err);
+            }
+        }
+        else if self.errors_file.exists() {
+            let _ = fs::remove_file(&self.errors_file);
+        }
+
+        self.all_failures.lock().unwrap().extend(dir_failures);
+    }
+}
+
+// Collects the results for one image while its jobs run on the pool. Handed
+// to the directory's index once the last job referencing it is done (or has
+// panicked).
+struct PendingImage {
+    dir: Arc<DirIndex>,
+    index: usize,
+    in_abspath: PathBuf,
+    in_relpath: PathBuf,
+    format: FileFormat,
+    out_abspath: PathBuf,
+    mtime: DateTime<Local>,
+    options: Arc<Options>,
+    state: Mutex<ImageState>,
+}
+
+struct ImageState {
+    stage: ProcessingStage,
+    failed: bool,
+    info: Option<FileInfo>,
+    thumbnails: Vec<Option<ThumbnailInfo>>,
+    thumbnails_done: usize,
+    failures: Vec<FileError>,
 }
 
-fn is_image (entry: &fs::DirEntry) -> bool {
-    let image_extensions = ["JPG", "jpg"];
-    let path = entry.path();
-    let ext = match path.extension() {
-        Some(e) => e.to_str().unwrap().clone(),
-        None => ""
+impl PendingImage {
+    fn enter(&self, stage: ProcessingStage) {
+        self.state.lock().unwrap().stage = stage;
+    }
+
+    // The image cannot be processed any further.
+    fn fail(&self, err: &Error) {
+        let mut state = self.state.lock().unwrap();
+        let f = image_failure(&self.in_abspath, &self.in_relpath, state.stage.clone(), err, &self.options);
+        state.failures.push(f);
+        state.failed = true;
+        state.info = None;
+    }
+
+    // Records a failure that does not keep the image out of the index.
+    fn report(&self, stage: ProcessingStage, err: &Display) {
+        let f = file_failure(&self.in_relpath, stage, err);
+        self.state.lock().unwrap().failures.push(f);
+    }
+
+    fn set_info(&self, info: FileInfo) {
+        self.state.lock().unwrap().info = Some(info);
+    }
+
+    fn expect_thumbnails(&self, count: usize) {
+        let mut state = self.state.lock().unwrap();
+        state.stage = ProcessingStage::Thumbnail;
+        state.thumbnails = vec![None; count];
+    }
+
+    // `Ok(None)` if the thumbnail was skipped.
+    fn set_thumbnail(&self, i: usize, result: Result<Option<ThumbnailInfo>, Error>) {
+        let mut state = self.state.lock().unwrap();
+        state.thumbnails_done += 1;
+        match result {
+            Ok(thumb) => state.thumbnails[i] = thumb,
+            Err(err) => {
+                let f = file_failure(&self.in_relpath, ProcessingStage::Thumbnail, &err);
+                state.failures.push(f);
+            }
+        }
+    }
+}
+
+impl Drop for PendingImage {
+    fn drop(&mut self) {
+        let state = match self.state.get_mut() {
+            Ok(state) => state,
+            Err(poisoned) => poisoned.into_inner()
+        };
+
+        if !state.failed && (state.info.is_none() || state.thumbnails_done < state.thumbnails.len()) {
+            let f = file_failure(&self.in_relpath, state.stage.clone(), &"processing panicked");
+            state.failures.push(f);
+        }
+
+        let thumbnail_sizes = state.thumbnails.iter().filter_map(|t| *t).collect::<Vec<_>>();
+        let info = state.info.take().map(|mut info| {
+            info.thumbnail_sizes = thumbnail_sizes;
+            info
+        });
+        self.dir.finish(self.index, info, state.failures.drain(..).collect());
+    }
+}
+
+// A decoded image, holding on to its share of the decode budget until the
+// last thumbnail job using it is done.
+struct Decoded {
+    img: SourceImage,
+    _permit: Option<Permit>,
+}
+
+fn process_image(image: Arc<PendingImage>, permit: Option<Permit>, pool: PoolHandle) {
+    let options = image.options.clone();
+
+    let hexdigest = match file_sha1(&image.in_abspath) {
+        Ok(h) => h,
+        Err(err) => return image.fail(&Error::Io(err))
     };
-    image_extensions.iter().any(|x| *x == ext)
+
+    // the header is shared by the metadata and all decoding below
+    image.enter(ProcessingStage::Metadata);
+    let header = match ImageHeader::read(&image.in_abspath, image.format) {
+        Ok(header) => header,
+        Err(err) => return image.fail(&err)
+    };
+    let m = Metadata::from_header(&header);
+
+    let mut info = FileInfo {
+        filename: String::new(),
+        filename_bytes: None,
+        format: Some(image.format),
+        sha1sum: hexdigest,
+        modified_time: image.mtime.clone(),
+        metadata: m,
+        thumbnail_sizes: Vec::new()
+    };
+    info.set_filename(&image.in_relpath);
+    image.set_info(info);
+
+    if !options.generate_thumbnails {
+        return;
+    }
+
+    image.enter(ProcessingStage::Decode);
+    let (mut full, mut from_preview): (Vec<usize>, Vec<usize>) = (0..options.thumbnails.len())
+        .partition(|&i| options.thumbnails[i].size > options.preview_max_size);
+    from_preview.sort_by(|&a, &b| options.thumbnails[b].size.cmp(&options.thumbnails[a].size));
+
+    // find a preview that covers as many of the small sizes as possible; the
+    // sizes it does not cover are made from the full image
+    let specs = |indices: &[usize]| indices.iter().map(|&i| options.thumbnails[i].clone()).collect::<Vec<_>>();
+    let mut preview = None;
+    while !from_preview.is_empty() {
+        match read_source_preview(&header, &specs(&from_preview)) {
+            Ok(Some(img)) => {
+                preview = Some(img);
+                break;
+            },
+            Ok(None) => full.push(from_preview.remove(0)),
+            Err(err) => {
+                warn!("could not read previews of '{}': {}", image.in_abspath.display(), err);
+                full.extend(from_preview.drain(..));
+            }
+        }
+    }
+
+    let decoded = match full.is_empty() {
+        false => match read_source(&header, &specs(&full)) {
+            Ok(img) => Some(Arc::new(Decoded { img: img, _permit: permit })),
+            // e.g. a RAW file without a preview; nothing is wrong with the
+            // file, so it is indexed without thumbnails
+            Err(err @ Error::NoPreview(_)) => return image.report(ProcessingStage::Thumbnail, &err),
+            Err(err) => return image.fail(&err)
+        },
+        true => None
+    };
+
+    image.expect_thumbnails(options.thumbnails.len());
+
+    let mut planned = Vec::new();
+    if let Some(img) = preview {
+        let from_preview = planned_thumbnails(&image, &img, from_preview, &mut planned);
+        make_thumbnails(&image, Arc::new(Decoded { img: img, _permit: None }), from_preview, &pool);
+    }
+    if let Some(decoded) = decoded {
+        let full = planned_thumbnails(&image, &decoded.img, full, &mut planned);
+        make_thumbnails(&image, decoded, full, &pool);
+    }
+}
+
+// Of the thumbnails `indices` (into `options.thumbnails`), returns those to
+// make from `source`. Thumbnails skipped for being larger than `source` and
+// repeats of a file already in `planned` (by dimensions and format; sizes
+// clamped to the source can coincide) are marked as done without a result.
+fn planned_thumbnails(
+    image: &PendingImage,
+    source: &SourceImage,
+    indices: Vec<usize>,
+    planned: &mut Vec<((u32, u32), OutputFormat)>) -> Vec<usize>
+{
+    let specs = &image.options.thumbnails;
+    indices.into_iter().filter(|&i| {
+        let file = thumbnail_dimensions(source, &specs[i]).map(|dims| (dims, specs[i].format));
+        match file {
+            Some(file) if !planned.contains(&file) => {
+                planned.push(file);
+                true
+            },
+            _ => {
+                image.set_thumbnail(i, Ok(None));
+                false
+            }
+        }
+    }).collect()
+}
+
+// Writes the thumbnails `indices` (into `options.thumbnails`) of `image`
+// from `source`.
+fn make_thumbnails(image: &Arc<PendingImage>, source: Arc<Decoded>, indices: Vec<usize>, pool: &PoolHandle) {
+    let options = &image.options;
+    match options.cascade_ratio {
+        Some(ratio) => {
+            // deriving smaller sizes from larger ones is sequential
+            let specs = indices.iter().map(|&i| options.thumbnails[i].clone()).collect::<Vec<_>>();
+            let results = make_thumbnails_cascaded (&source.img, &specs, ratio, &image.out_abspath);
+            for (&i, r) in indices.iter().zip(results.into_iter()) {
+                image.set_thumbnail(i, r);
+            }
+        },
+        None => {
+            for i in indices {
+                let spec = options.thumbnails[i].clone();
+                let image = image.clone();
+                let source = source.clone();
+                pool.execute(move || {
+                    let r = make_thumbnail (&source.img, &spec, &image.out_abspath);
+                    image.set_thumbnail(i, r);
+                });
+            }
+        }
+    }
+}
+
+// Applies the `.thumbrs.toml` and `.thumbrsignore` of `input_path`, if
+// there are any, to the options inherited from the parent directory. Returns
+// `None` if the directory is to be skipped.
+fn dir_options(
+    input_prefix: &Path,
+    input_path: &Path,
+    ctx: &Context,
+    inherited: Arc<Options>) -> Option<Arc<Options>>
+{
+    let config_file = input_path.join(DIR_CONFIG_FILE);
+    let ignore_file = input_path.join(IGNORE_FILE);
+    if !config_file.is_file() && !ignore_file.is_file() {
+        return Some(inherited);
+    }
+
+    let dir_relpath = strip_prefix(&input_path, &input_prefix).unwrap();
+    let config_failure = |file: &Path, err: &Display| {
+        let relpath = strip_prefix(file, &input_prefix).unwrap();
+        let f = file_failure(&relpath, ProcessingStage::Config, err);
+        ctx.failures.lock().unwrap().push(f);
+    };
+
+    let mut options = (*inherited).clone();
+
+    if config_file.is_file() {
+        // a broken configuration might have excluded files or changed the
+        // sizes, so rather skip the directory than guess
+        let skip = |err: &Error| -> Option<Arc<Options>> {
+            config_failure(&config_file, &format!("{}; skipping the directory", err));
+            None
+        };
+
+        let dir_config = match DirConfig::load(&config_file) {
+            Ok(c) => c,
+            Err(err) => return skip(&err)
+        };
+
+        if dir_config.skip {
+            return None;
+        }
+
+        if let Some(metadata_only) = dir_config.metadata_only {
+            options.generate_thumbnails = !metadata_only && !ctx.force_metadata_only;
+        }
+        if let Some(ref profile) = dir_config.profile {
+            match ctx.config.profile_thumbnails(profile) {
+                Ok(thumbnails) => options.thumbnails = thumbnails.to_vec(),
+                Err(err) => return skip(&err)
+            }
+        }
+        if let Some(thumbnails) = dir_config.thumbnails {
+            options.thumbnails = thumbnails;
+        }
+        if let Some(quality) = dir_config.quality {
+            for t in options.thumbnails.iter_mut() {
+                t.quality = quality;
+            }
+        }
+        for pattern in dir_config.exclude.iter() {
+            if let Err(err) = options.filter.add_exclude(&dir_relpath, pattern) {
+                return skip(&err);
+            }
+        }
+    }
+
+    if ignore_file.is_file() {
+        match options.filter.add_ignore_file(&dir_relpath, &ignore_file) {
+            Ok(invalid) => for (line, err) in invalid {
+                config_failure(&ignore_file, &format!("line {}: {}; skipping the line", line, err));
+            },
+            Err(err) => {
+                config_failure(&ignore_file, &format!("{}; skipping the directory", err));
+                return None;
+            }
+        }
+    }
+
+    Some(Arc::new(options))
+}
+
+// Formats the thumbnail pipeline can decode.
+const SUPPORTED_FORMATS: &'static [FileFormat] = &[
+    FileFormat::Jpeg,
+    FileFormat::Png,
+    FileFormat::Gif,
+    FileFormat::Tiff,
+    FileFormat::WebP,
+    FileFormat::Bmp,
+    FileFormat::Raw
+];
+
+// Detects the format of `path` from its contents. Returns `Ok(None)` for
+// files that are not (supported) images, and an error for files that are
+// named like images but cannot be processed.
+fn detect_image (path: &Path) -> Result<Option<FileFormat>, (ProcessingStage, String)> {
+    let named_format = FileFormat::from_extension(path);
+    match detect_format(path) {
+        Ok(Some(format)) => {
+            if !SUPPORTED_FORMATS.contains(&format) {
+                return match named_format {
+                    Some(named) if named != format =>
+                        Err((ProcessingStage::Detect, format!("unsupported {:?} file named like a {:?} file", format, named))),
+                    _ => Ok(None)
+                };
+            }
+            Ok(Some(format))
+        },
+        Ok(None) => match named_format {
+            Some(named) => Err((ProcessingStage::Decode, format!("not a {:?} file", named))),
+            None => Ok(None)
+        },
+        Err(err) => match named_format {
+            Some(_) => Err((ProcessingStage::Decode, err.to_string())),
+            None => Ok(None)
+        }
+    }
 }
 
 fn tree_prefix (ancestor_at_end: &Vec<bool>) -> String {
@@ -123,13 +620,9 @@ fn tree_prefix (ancestor_at_end: &Vec<bool>) -> String {
         .collect::<String>()
 }
 
-fn tree_line (progress: Option<(u32, u32)>, ancestor_at_end: &Vec<bool>, has_subcontent: bool, suffix: &str) -> String {
-    let s = match progress {
-        Some(p) => format!("{:02}/{:02} ", p.0, p.1),
-        None => "      ".to_string()
-    };
-
-    s + &tree_prefix(ancestor_at_end)
+fn tree_line (ancestor_at_end: &Vec<bool>, has_subcontent: bool, suffix: &str) -> String {
+    "      ".to_string()
+      + &tree_prefix(ancestor_at_end)
       + if has_subcontent { "├── " } else { "└── " }
       + suffix
 }
@@ -138,47 +631,112 @@ fn walk_filetree_impl(
     input_prefix: &Path,
     input_path: &Path,
     output_path: &Path,
-    options: &Options,
+    ctx: &Context,
+    inherited_options: Arc<Options>,
     ancestor_at_end: Vec<bool>)
 {
+    let options = match dir_options(input_prefix, input_path, ctx, inherited_options) {
+        Some(options) => options,
+        None => return
+    };
+
     let dir_iter = match fs::read_dir(input_path) {
         Ok(i) => i,
-        Err(_) => {
+        Err(err) => {
+            let f = file_failure(&strip_prefix(&input_path, &input_prefix).unwrap(), ProcessingStage::Detect, &err);
+            ctx.failures.lock().unwrap().push(f);
             return;
         }
     };
 
-    let mut dir_contents = dir_iter
-        .into_iter()
-        .filter_map(|e| e.ok())
-        .collect::<Vec<_>>();
+    let mut detect_failures = Vec::<FileError>::new();
+
+    // entries with whether they are directories
+    let mut dir_contents = Vec::new();
+    for e in dir_iter.filter_map(|e| e.ok()) {
+        match e.metadata() {
+            Ok(meta) => dir_contents.push((e, meta.is_dir())),
+            // e.g. deleted since listing the directory
+            Err(err) => {
+                let path = e.path();
+                let relpath = strip_prefix(&path, &input_prefix).unwrap();
+                detect_failures.push(file_failure(&relpath, ProcessingStage::Detect, &err));
+            }
+        }
+    }
 
     dir_contents
-        .sort_by(|a, b| {
+        .sort_by(|&(ref a, _), &(ref b, _)| {
             let a = a.path();
             let b = b.path();
             a.cmp(&b)
         });
 
     let sub_dirs = dir_contents.iter()
-        .filter(|e| is_dir(*e, &options))
+        .filter(|&&(ref e, is_dir)| {
+            is_dir && !options.filter.is_excluded(&strip_prefix(&e.path(), &input_prefix).unwrap(), true)
+        })
+        .map(|&(ref e, _)| e)
         .collect::<Vec<_>>();
 
-    let jpegs = dir_contents.iter()
-        .filter(|e| !e.metadata().unwrap().is_dir())
-        .filter(|e| is_image(*e))
-        .collect::<Vec<_>>();
+    let files = dir_contents.iter()
+        .filter(|&&(_, is_dir)| !is_dir)
+        .map(|&(ref e, _)| e)
+        .filter(|e| {
+            let path = e.path();
+            let relpath = strip_prefix(&path, &input_prefix).unwrap();
+            !options.filter.is_excluded(&relpath, false) && options.filter.is_included(&relpath)
+        });
+
+    // select images by their contents, not by their extension
+    let mut images = Vec::new();
+    for e in files {
+        let path = e.path();
+        match detect_image(&path) {
+            Ok(Some(format)) => {
+                if FileFormat::from_extension(&path) != Some(format) {
+                    let relpath = strip_prefix(&path, &input_prefix).unwrap();
+                    ctx.misnamed.lock().unwrap().push((relpath.to_path_buf(), format));
+                }
+                images.push((e, format))
+            },
+            Ok(None) => (),
+            Err((stage, msg)) => {
+                let relpath = strip_prefix(&path, &input_prefix).unwrap();
+                detect_failures.push(file_failure(&relpath, stage, &msg));
+            }
+        }
+    }
 
-    if sub_dirs.len() == 0 && jpegs.len() == 0 {
+    if sub_dirs.len() == 0 && images.len() == 0 && detect_failures.len() == 0 {
         return;
     }
 
-    fs::create_dir_all(output_path)
-        .ok()
-        .expect("Could not create output dir");
+    let dir_relpath = strip_prefix(&input_path, &input_prefix).unwrap();
+
+    if let Err(err) = fs::create_dir_all(output_path) {
+        let f = file_failure(&dir_relpath, ProcessingStage::Index, &err);
+        ctx.failures.lock().unwrap().push(f);
+        return;
+    }
+
+    // `.` and `..` have no name of their own
+    let dir_name = input_path.file_name()
+        .map(|name| name.to_os_string())
+        .or_else(|| fs::canonicalize(input_path).ok().and_then(|path| path.file_name().map(|name| name.to_os_string())));
+    let dir_name = match dir_name {
+        Some(name) => name,
+        None => {
+            let f = file_failure(&dir_relpath, ProcessingStage::Index, &"cannot name an index after the directory");
+            ctx.failures.lock().unwrap().push(f);
+            return;
+        }
+    };
 
     // where to write an index for this directory's images
-    let json_file_name = "_".to_string() + input_path.file_name().unwrap().to_str().unwrap() + ".json";
+    let mut json_file_name = OsString::from("_");
+    json_file_name.push(&dir_name);
+    json_file_name.push(".json");
     let json_file = output_path.join(&json_file_name);
 
     let mut existing_file_infos = Vec::<FileInfo>::new();
@@ -186,17 +744,27 @@ fn walk_filetree_impl(
     if let Ok(f) = File::open(&json_file) {
         // println!("reading from {:?}", f);
         let x : serde_json::error::Result<Vec<FileInfo>> = serde_json::from_reader(f);
-        existing_file_infos = x.ok().unwrap();
+        match x {
+            Ok(infos) => existing_file_infos = infos,
+            Err(err) => warn!("ignoring unreadable index '{}': {}", json_file.display(), err)
+        }
     }
     //println!("existing_file_infos {:?}", existing_file_infos);
 
-    let mut generation_infos = Vec::<FileInfo>::new();
-
-    //assert!(sizes.len() == qualities.len());
-    let thumbnail_count = options.thumbnail_sizes.len();
-
-    let jpeg_count = jpegs.len();
-    for (i, curr_entry) in jpegs.iter().enumerate() {
+    let dir_index = Arc::new(DirIndex {
+        json_file: json_file.clone(),
+        json_relpath: dir_relpath.join(&json_file_name),
+        errors_file: output_path.join("_errors.json"),
+        options: options.clone(),
+        all_failures: ctx.failures.clone(),
+        state: Mutex::new(DirState {
+            infos: vec![None; images.len()],
+            failures: detect_failures
+        })
+    });
+
+    let image_count = images.len();
+    for (i, &(curr_entry, format)) in images.iter().enumerate() {
 
         // absolute path to source image
         let in_abspath = curr_entry.path();
@@ -208,7 +776,7 @@ fn walk_filetree_impl(
         let in_fname = strip_prefix(&in_abspath, &input_path).unwrap();
 
         let prev_info = existing_file_infos
-            .iter().find(|&e| e.filename == in_relpath.to_str().unwrap());
+            .iter().find(|&e| e.path() == in_relpath);
         
         //println!("* prev info {:?} {:?}", in_relpath, prev_info);
 
@@ -226,88 +794,45 @@ fn walk_filetree_impl(
         }
 
         if regenerate {
-            let hexdigest = file_sha1(&curr_entry.path())
-                .ok()
-                .expect("Could not compute SHA1 sum");
-
-            // filename of output image, corresponding to source image
-            // (we will append suffixes to this for different thumbnail sizes)
-            let out_abspath = output_path.join(in_fname);
-        
-            let has_subcontent = i < jpeg_count - 1;
-
-            let update_line = |curr_i: u32| {
-                let t = tree_line(Some((curr_i as u32, (thumbnail_count+1) as u32)), &ancestor_at_end, has_subcontent, in_fname.to_str().unwrap());
-                let _ = std::io::stdout().write( (String::new() + "\r" + &t).as_bytes());
-                let _ = std::io::stdout().flush();
+            let has_subcontent = i < image_count - 1;
+            println!("{}", tree_line(&ancestor_at_end, has_subcontent, &in_fname.to_string_lossy()));
+
+            let image = Arc::new(PendingImage {
+                dir: dir_index.clone(),
+                index: i,
+                in_abspath: in_abspath.clone(),
+                in_relpath: in_relpath.to_path_buf(),
+                format: format,
+                // filename of output image, corresponding to source image
+                // (we will append suffixes to this for different thumbnail sizes)
+                out_abspath: output_path.join(in_fname),
+                mtime: mtime,
+                options: options.clone(),
+                state: Mutex::new(ImageState {
+                    stage: ProcessingStage::Hash,
+                    failed: false,
+                    info: None,
+                    thumbnails: Vec::new(),
+                    thumbnails_done: 0,
+                    failures: Vec::new()
+                })
+            });
+
+            // blocks while too many full resolution images are in flight
+            let permit = match options.generate_thumbnails {
+                true => Some(Semaphore::acquire(&ctx.decode_budget)),
+                false => None
             };
-
-            let m = Metadata::from(&in_abspath);
-
-            ////
-
-            let thumbnail_sizes = match options.generate_thumbnails {
-                false => Vec::<(u32,u32)>::new (),
-                true => {
-                    let mut tsizes = Vec::<(u32,u32)>::new ();
-                    let (tx, rx) = mpsc::channel();
-
-                    let img = read_and_rotate (&in_abspath);
-
-                    update_line (1);
-
-                    let shared_img = Arc::new(img);
-
-                    for (size, quality) in options.thumbnail_sizes.iter().cloned().zip(options.thumbnail_qualities.iter().cloned()) {
-                        let tx = tx.clone();
-
-                        let out = out_abspath.clone ();
-
-                        let local_img = shared_img.clone();
-                        thread::spawn(move || {
-                            //println!("MAKE THUMB {} {} {:?}", size, quality, &out);
-                            let (w,h) = make_thumbnail (&local_img, size, quality, &out);
-                            tx.send((w, h)).unwrap();
-                        });
-                    }
-
-                    for i in 0..thumbnail_count {
-                        let (w,h) = rx.recv().unwrap();
-
-                        update_line ((i+2) as u32);
-
-                        tsizes.push((w, h));
-                    }
-                    tsizes
-                }
-            };
-
-            let _ = std::io::stdout().write("\n".as_bytes());
-            let _ = std::io::stdout().flush();
-
-            let timestamp = mtime; 
-            let file_info = FileInfo { filename: in_relpath.to_str().unwrap().to_string(), sha1sum: hexdigest, modified_time: timestamp, metadata: m.unwrap(), thumbnail_sizes: thumbnail_sizes };
-
-            generation_infos.push(file_info);
+            let pool = ctx.pool.handle();
+            ctx.pool.execute(move || process_image(image, permit, pool));
         }
         else if prev_info.is_some() {
-            generation_infos.push(prev_info.unwrap().clone());
+            dir_index.finish(i, Some(prev_info.unwrap().clone()), Vec::new());
         }
     }
 
-
-    if generation_infos.len() > 0 {
-        let j = serde_json::to_string_pretty(&generation_infos).unwrap();
-
-        let msg = String::new() + "{meta: " + &json_file_name + "}";
-        println!("      {}{}", tree_prefix(&ancestor_at_end), &msg);
-       
-        let mut f = File::create(json_file).unwrap();
-        match f.write_all(j.as_bytes()) {
-            Ok (_) => (),
-            Err (_) => warn!("Error writing json to disk")
-        }
-    }
+    // the index is written once all images queued above are done
+    drop(dir_index);
 
     let subdir_count = sub_dirs.len();
     for (i, dir) in sub_dirs.iter().enumerate() {
@@ -317,16 +842,16 @@ fn walk_filetree_impl(
 
         if fs::read_dir(&path).is_ok() {
             let has_subcontent = i < subdir_count-1;
-            let t = tree_line(None, &ancestor_at_end, has_subcontent, relative_file.to_str().unwrap());
+            let t = tree_line(&ancestor_at_end, has_subcontent, &relative_file.to_string_lossy());
             println!("{}", t);
             let mut a = ancestor_at_end.clone();
             a.push(has_subcontent);
-            walk_filetree_impl(&input_prefix, &dir.path(), &out_file, &options, a);
+            walk_filetree_impl(&input_prefix, &dir.path(), &out_file, ctx, options.clone(), a);
         }
         else {
             let has_subcontent = false;
-            let suffix : String = String::new() + relative_file.to_str().unwrap() + " [inaccessible]";
-            let t = tree_line(None, &ancestor_at_end, has_subcontent, &suffix);
+            let suffix : String = String::new() + &relative_file.to_string_lossy() + " [inaccessible]";
+            let t = tree_line(&ancestor_at_end, has_subcontent, &suffix);
             println!("{}", t);
         }
 
@@ -339,34 +864,111 @@ struct Args {
     arg_outpath: String,
     flag_help: bool,
     flag_no_thumbs: bool,
+    flag_quarantine: Option<String>,
+    flag_cascade: Option<f64>,
+    flag_preview_max: u32,
+    flag_preview_only: bool,
+    flag_jobs: usize,
+    flag_max_decodes: usize,
+    flag_config: Option<String>,
+    flag_profile: Option<String>,
+    flag_exclude: Vec<String>,
+    flag_include: Vec<String>,
 }
 
 const USAGE: &'static str = "
 Thumbnail and image metadata extractor.
 
 Usage:
-  thumbrs [-d] <inpath> <outpath>
+  thumbrs [-d] [-c <file>] [-p <name>] [-j <n>] [--max-decodes <n>] [--quarantine <dir>] [--cascade <ratio>] [--preview-max <px> | --preview-only] [-e <glob>...] [-i <glob>...] <inpath> <outpath>
 
 Options:
-  -h --help       Show this screen.
-  -d --no-thumbs  Do not generate thumbnails (but extract metadata).
+  -h --help            Show this screen.
+  -c --config <file>   Read thumbnail sizes and excluded directories from a
+                       TOML (or .json) file.
+  -p --profile <name>  Use the thumbnail sizes of this profile instead of
+                       the one selected in the configuration.
+  -d --no-thumbs       Do not generate thumbnails (but extract metadata).
+  -e --exclude <glob>  Skip files and directories matching this gitignore
+                       style pattern (can be given multiple times).
+  -i --include <glob>  Only process files matching one of these patterns
+                       (relative to <inpath>).
+  -j --jobs <n>        Number of worker threads [default: 4].
+  --max-decodes <n>    Maximum number of full resolution images kept in
+                       memory at the same time [default: 2].
+  --quarantine <dir>   Move damaged files into <dir>.
+  --cascade <ratio>    Derive smaller thumbnails from larger ones, using a
+                       thumbnail as source if it is at least <ratio> times
+                       the requested size (e.g. 1.5). Faster, but lower
+                       quality for small ratios.
+  --preview-max <px>   Make thumbnails up to this size from previews
+                       embedded in the image, falling back to decoding the
+                       full image if there is no suitable one [default: 100].
+  --preview-only       Make all thumbnails from embedded previews if
+                       possible.
 ";
 
+fn exit_with(err: Error) -> ! {
+    println!("{}", err);
+    std::process::exit(1);
+}
+
+// docopt only handles unicode arguments, so paths given as arguments are
+// looked up among the raw ones.
+fn path_arg(arg: &str) -> PathBuf {
+    std::env::args_os()
+        .find(|a| a.to_string_lossy() == arg)
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(arg))
+}
+
 fn main() {
+    let argv = std::env::args_os().map(|a| a.to_string_lossy().into_owned()).collect::<Vec<_>>();
     let args: Args = Docopt::new(USAGE)
-        .and_then(|d| d.decode())
+        .and_then(|d| d.argv(argv.into_iter()).decode())
         .unwrap_or_else(|e| e.exit());
 
-    let inpath = Path::new(&args.arg_inpath);
-    let outpath = Path::new(&args.arg_outpath);
+    let inpath = path_arg(&args.arg_inpath);
+    let outpath = path_arg(&args.arg_outpath);
     let no_thumbs = args.flag_no_thumbs;
 
+    let mut config = match args.flag_config {
+        Some(ref path) => Config::load(&path_arg(path)).unwrap_or_else(|err| exit_with(err)),
+        None => Config::default()
+    };
+    if let Some(profile) = args.flag_profile {
+        config.profile = profile;
+    }
+    let thumbnails = config.thumbnails().unwrap_or_else(|err| exit_with(err)).to_vec();
+
+    if let Some(ratio) = args.flag_cascade {
+        // also rejects NaN
+        if !(ratio >= 1.0) {
+            exit_with(Error::Config(format!("--cascade ratio must be at least 1.0, got {}", ratio)));
+        }
+    }
+
+    let mut filter = PathFilter::new();
+    for pattern in config.exclude.iter().chain(args.flag_exclude.iter()) {
+        filter.add_exclude(Path::new(""), pattern).unwrap_or_else(|err| exit_with(err));
+    }
+    for pattern in config.include.iter().chain(args.flag_include.iter()) {
+        filter.add_include(pattern).unwrap_or_else(|err| exit_with(err));
+    }
+
     let opt = Options {
         generate_thumbnails: !no_thumbs,
         compare_by_hash: false,
-        thumbnail_sizes: vec![100, 200, 300, 640, 800, 1024, 1920],
-        thumbnail_qualities: vec![75, 75, 75, 88, 88, 88, 88],
-        hidden_dirs : vec![String::from("0-sterne"), String::from("raw")]
+        thumbnails: thumbnails,
+        filter: filter,
+        quarantine_dir: args.flag_quarantine.as_ref().map(|dir| path_arg(dir)),
+        cascade_ratio: args.flag_cascade,
+        preview_max_size: match args.flag_preview_only {
+            true => u32::max_value(),
+            false => args.flag_preview_max
+        },
+        jobs: cmp::max(1, args.flag_jobs),
+        max_decodes: cmp::max(1, args.flag_max_decodes)
     };
 
     println!("Rust thumbnail and meta-data extractor.");
@@ -376,5 +978,5 @@ fn main() {
     println!("  out: {}", &args.arg_outpath);
     println!("");
 
-    walk_filetree(&inpath, &outpath, &opt);
+    walk_filetree(&inpath, &outpath, &opt, &config);
 }
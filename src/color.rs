@@ -0,0 +1,61 @@
+//! Color management with Little CMS: images tagged with an ICC profile
+//! (e.g. Adobe RGB or Display P3) are converted to sRGB, which is what
+//! browsers assume for untagged thumbnails.
+
+use std::mem;
+use std::slice;
+
+use lcms2::{InfoType, Intent, Locale, PixelFormat, Profile, Transform};
+
+/// Layout of the 8 bit pixels passed to `to_srgb`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PixelLayout {
+    Gray,
+    Rgb,
+    Cmyk,
+    /// CMYK as written by Photoshop, with 255 meaning no ink.
+    InvertedCmyk,
+}
+
+/// Description of the ICC profile `icc`, e.g. "Adobe RGB (1998)".
+pub fn profile_description(icc: &[u8]) -> Option<String> {
+    Profile::new_icc(icc).ok().and_then(|profile| profile.info(InfoType::Description, Locale::none()))
+}
+
+/// Whether `icc` is an sRGB profile, whose pixels need no conversion.
+pub fn is_srgb(icc: &[u8]) -> bool {
+    // e.g. "sRGB IEC61966-2.1" or "sRGB built-in"
+    profile_description(icc).map_or(false, |desc| desc.starts_with("sRGB"))
+}
+
+/// The ICC profile of sRGB, for embedding into thumbnails.
+pub fn srgb_profile() -> Vec<u8> {
+    Profile::new_srgb().icc().expect("could not serialize the sRGB profile")
+}
+
+/// Converts `pixels`, in the color space described by `icc`, to sRGB and
+/// returns them as RGB.
+pub fn to_srgb(pixels: &[u8], layout: PixelLayout, icc: &[u8]) -> Result<Vec<u8>, String> {
+    let source = try!(Profile::new_icc(icc).map_err(|err| err.to_string()));
+    match layout {
+        PixelLayout::Gray => transform::<u8>(pixels, &source, PixelFormat::GRAY_8),
+        PixelLayout::Rgb => transform::<[u8; 3]>(pixels, &source, PixelFormat::RGB_8),
+        PixelLayout::Cmyk => transform::<[u8; 4]>(pixels, &source, PixelFormat::CMYK_8),
+        PixelLayout::InvertedCmyk => transform::<[u8; 4]>(pixels, &source, PixelFormat::CMYK_8_REV),
+    }
+}
+
+// `Px` is a byte (array) of the size of one pixel in `format`.
+fn transform<Px: Copy>(pixels: &[u8], source: &Profile, format: PixelFormat) -> Result<Vec<u8>, String> {
+    let srgb = Profile::new_srgb();
+    let transform = try!(Transform::<Px, [u8; 3]>::new(source, format, &srgb, PixelFormat::RGB_8, Intent::Perceptual)
+        .map_err(|err| err.to_string()));
+
+    // byte arrays have no alignment requirements
+    let input = unsafe {
+        slice::from_raw_parts(pixels.as_ptr() as *const Px, pixels.len() / mem::size_of::<Px>())
+    };
+    let mut output = vec![[0u8; 3]; input.len()];
+    transform.transform_pixels(input, &mut output);
+    Ok(output.iter().flat_map(|px| px.iter().cloned()).collect())
+}
@@ -0,0 +1,380 @@
+//! Configuration of thumbnail sizes and excluded directories, loaded from a
+//! TOML or JSON file.
+//!
+//! ```toml
+//! profile = "web"
+//! exclude = ["raw/", "private/", "*.tmp.jpg"]
+//! include = ["**/*.jpg"]
+//!
+//! [[profiles.web]]
+//! size = 200
+//! quality = 75
+//!
+//! [[profiles.web]]
+//! size = 1920
+//! quality = 88
+//! format = "jpeg"
+//! fit = "longest-side"
+//! upscale = "copy"
+//! filter = "lanczos3"
+//! linear_light = true
+//! subsampling = "4:2:0"
+//!
+//! # center-cropped squares for the gallery grid
+//! [[profiles.web]]
+//! size = 150
+//! fit = "cover"
+//! sharpen_amount = 0.6
+//! sharpen_radius = 0.8
+//!
+//! [[profiles.web]]
+//! size = 1920
+//! quality = 80
+//! format = "webp"
+//! ```
+//!
+//! `fit` is one of `longest-side` (the default), `width`, `height`,
+//! `contain` (within a box) and `cover` (filling a box, cropped); the box is
+//! given by `width` and `height`, which default to `size`.
+//!
+//! `upscale` decides what happens to thumbnails larger than the source
+//! image: `skip` (the default), `clamp` to the size of the source, `copy` the
+//! original file (or re-encode it at full size) or `upscale` anyway, see
+//! `UpscalePolicy`.
+//!
+//! `filter` is the resampling filter, one of `catmull-rom` (the default),
+//! `mitchell`, `lanczos3` and `box` (or `area`); with `linear_light = true`
+//! the image is resampled in linear light instead of in sRGB.
+//!
+//! `sharpen_amount` (e.g. `0.5` for 50%) sharpens the thumbnail with an
+//! unsharp mask of `sharpen_radius` (pixels, default 1.0) and
+//! `sharpen_threshold` (0-255, default 0), see `UnsharpMask`.
+//!
+//! Supported formats are `jpeg`, `webp`, `webp-lossless`, `png` and, when
+//! built with the `avif` feature, `avif`. JPEG thumbnails can further set
+//! `progressive`, `trellis`, `quant_table`, `subsampling` (`"4:4:4"` or
+//! `"4:2:0"`), `optimize_coding`, `smoothing` and `embed_icc` (tag the
+//! thumbnail with the sRGB ICC profile), see `JpegEncodeOptions`, and
+//! `target_size` (bytes) or `target_ssim` to search for the quality, see
+//! `QualityTarget`.
+//!
+//! Directories can contain a `.thumbrs.toml` file overriding these settings
+//! for themselves and all subdirectories, see `DirConfig`. See `filter` for
+//! the syntax of `exclude` and `include` patterns.
+
+use std::cmp;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use serde::Deserialize;
+use serde_json;
+use toml;
+
+use encode::QualityTarget;
+use error::Error;
+use jpegimpex::JpegEncodeOptions;
+use resample::ResizeFilter;
+use sharpen::UnsharpMask;
+use thumbnail::{FitMode, OutputFormat, UpscalePolicy};
+
+#[cfg(not(feature = "serde_macros"))]
+include!(concat!(env!("OUT_DIR"), "/config.rs"));
+
+#[cfg(feature = "serde_macros")]
+include!("config.rs.in");
+
+const DEFAULT_QUALITY: u8 = 88;
+
+/// Name of the per-directory configuration file.
+pub const DIR_CONFIG_FILE: &'static str = ".thumbrs.toml";
+
+/// One thumbnail to generate per image.
+#[derive(Debug, Clone)]
+pub struct ThumbnailSpec {
+    pub size: u32,
+    pub quality: u8,
+    pub format: OutputFormat,
+    pub fit: FitMode,
+    /// What to do if the thumbnail would be larger than the source image.
+    pub upscale: UpscalePolicy,
+    /// Filter used to resize the image.
+    pub filter: ResizeFilter,
+    /// Resize in linear light rather than on the sRGB encoded values.
+    pub linear_light: bool,
+    /// Sharpening applied after resizing.
+    pub sharpen: Option<UnsharpMask>,
+    /// Encoder settings, if `format` is JPEG.
+    pub jpeg: JpegEncodeOptions,
+    /// Search for the quality (up to `quality`) meeting this target; JPEG
+    /// only.
+    pub target: Option<QualityTarget>,
+}
+
+impl ThumbnailSpec {
+    pub fn new(size: u32, quality: u8) -> ThumbnailSpec {
+        ThumbnailSpec {
+            size: size,
+            quality: quality,
+            format: OutputFormat::Jpeg,
+            fit: FitMode::LongestSide,
+            upscale: UpscalePolicy::Skip,
+            filter: ResizeFilter::CatmullRom,
+            linear_light: false,
+            sharpen: None,
+            jpeg: JpegEncodeOptions::default(),
+            target: None
+        }
+    }
+
+    fn from_raw(raw: RawThumbnailSpec) -> Result<ThumbnailSpec, Error> {
+        let fit = try!(parse_fit(&raw));
+        // the size of `contain` and `cover` thumbnails is their box
+        let size = match (raw.size, fit) {
+            (_, FitMode::Contain(w, h)) | (_, FitMode::Cover(w, h)) => cmp::max(w, h),
+            (Some(size), _) => size,
+            (None, _) => return Err(Error::Config("thumbnail without size".to_string()))
+        };
+        if size == 0 {
+            return Err(Error::Config("thumbnail of size 0".to_string()));
+        }
+
+        let quality = try!(check_quality(raw.quality.unwrap_or(DEFAULT_QUALITY)));
+        let mut spec = ThumbnailSpec::new(size, quality);
+        spec.fit = fit;
+        if let Some(upscale) = raw.upscale {
+            spec.upscale = try!(upscale.parse().map_err(Error::Config));
+        }
+        if let Some(format) = raw.format {
+            spec.format = try!(format.parse().map_err(Error::Config));
+        }
+        if let Some(filter) = raw.filter {
+            spec.filter = try!(filter.parse().map_err(Error::Config));
+        }
+        spec.linear_light = raw.linear_light.unwrap_or(false);
+        spec.sharpen = try!(parse_sharpen(&raw));
+
+        {
+            let jpeg = &mut spec.jpeg;
+            jpeg.progressive = raw.progressive.unwrap_or(jpeg.progressive);
+            jpeg.trellis = raw.trellis.unwrap_or(jpeg.trellis);
+            if let Some(table) = raw.quant_table {
+                if table > 8 {
+                    return Err(Error::Config(format!("unknown quant_table {}, expected 0-8", table)));
+                }
+                jpeg.quant_table = Some(table);
+            }
+            if let Some(subsampling) = raw.subsampling {
+                jpeg.subsampling = try!(subsampling.parse().map_err(Error::Config));
+            }
+            jpeg.optimize_coding = raw.optimize_coding.unwrap_or(jpeg.optimize_coding);
+            jpeg.smoothing = cmp::min(100, raw.smoothing.unwrap_or(jpeg.smoothing));
+            jpeg.embed_icc_profile = raw.embed_icc.unwrap_or(jpeg.embed_icc_profile);
+        }
+
+        spec.target = match (raw.target_size, raw.target_ssim) {
+            (Some(_), Some(_)) => return Err(Error::Config("only one of target_size and target_ssim can be set".to_string())),
+            (Some(bytes), None) => Some(QualityTarget::Bytes(bytes)),
+            (None, Some(ssim)) if ssim > 0.0 && ssim <= 1.0 => Some(QualityTarget::Ssim(ssim)),
+            (None, Some(ssim)) => return Err(Error::Config(format!("target_ssim {} is not within (0, 1]", ssim))),
+            (None, None) => None
+        };
+        if spec.target.is_some() && spec.format != OutputFormat::Jpeg {
+            return Err(Error::Config(format!("target_size and target_ssim are only supported for JPEG, not {:?}", spec.format)));
+        }
+        Ok(spec)
+    }
+}
+
+// `contain` and `cover` take their box from `width` and `height`, either of
+// which defaults to `size`.
+fn parse_fit(raw: &RawThumbnailSpec) -> Result<FitMode, Error> {
+    let fit = match raw.fit {
+        Some(ref fit) => &fit[..],
+        None => return Ok(FitMode::LongestSide)
+    };
+    if fit != "contain" && fit != "cover" {
+        return fit.parse().map_err(Error::Config);
+    }
+
+    let box_w = try!(raw.width.or(raw.size)
+        .ok_or_else(|| Error::Config(format!("fit = \"{}\" needs a width or size", fit))));
+    let box_h = try!(raw.height.or(raw.size)
+        .ok_or_else(|| Error::Config(format!("fit = \"{}\" needs a height or size", fit))));
+    if box_w == 0 || box_h == 0 {
+        return Err(Error::Config(format!("empty box {}x{}", box_w, box_h)));
+    }
+    Ok(match fit {
+        "contain" => FitMode::Contain(box_w, box_h),
+        _ => FitMode::Cover(box_w, box_h)
+    })
+}
+
+// Sharpening is enabled by `sharpen_amount`; radius and threshold have
+// defaults.
+fn parse_sharpen(raw: &RawThumbnailSpec) -> Result<Option<UnsharpMask>, Error> {
+    let amount = match raw.sharpen_amount {
+        Some(amount) => amount,
+        None if raw.sharpen_radius.is_some() || raw.sharpen_threshold.is_some() =>
+            return Err(Error::Config("sharpen_radius and sharpen_threshold need a sharpen_amount".to_string())),
+        None => return Ok(None)
+    };
+    let radius = raw.sharpen_radius.unwrap_or(1.0);
+    if !(amount > 0.0) || !(radius > 0.0) {
+        return Err(Error::Config(format!("sharpen_amount {} and sharpen_radius {} must be positive", amount, radius)));
+    }
+    Ok(Some(UnsharpMask {
+        radius: radius,
+        amount: amount,
+        threshold: raw.sharpen_threshold.unwrap_or(0)
+    }))
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Name of the profile in `profiles` to use.
+    pub profile: String,
+    /// Named lists of thumbnails to generate.
+    pub profiles: BTreeMap<String, Vec<ThumbnailSpec>>,
+    /// Patterns of files and directories that are skipped.
+    pub exclude: Vec<String>,
+    /// If not empty, only files matching one of these patterns are processed.
+    pub include: Vec<String>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        let sizes = [(100, 75), (200, 75), (300, 75), (640, 88), (800, 88), (1024, 88), (1920, 88)];
+
+        let mut profiles = BTreeMap::new();
+        profiles.insert("default".to_string(), sizes.iter().map(|&(s, q)| ThumbnailSpec::new(s, q)).collect());
+
+        Config {
+            profile: "default".to_string(),
+            profiles: profiles,
+            exclude: Vec::new(),
+            include: Vec::new()
+        }
+    }
+}
+
+impl Config {
+    /// Reads a configuration file; files ending in `.json` are parsed as
+    /// JSON, everything else as TOML. Profiles not given in the file are
+    /// taken from the defaults.
+    pub fn load(path: &Path) -> Result<Config, Error> {
+        let raw: RawConfig = try!(read_config_file(path));
+
+        let mut config = Config::default();
+        if let Some(profile) = raw.profile {
+            config.profile = profile;
+        }
+        for (name, specs) in raw.profiles {
+            let specs = try!(specs.into_iter().map(ThumbnailSpec::from_raw).collect());
+            config.profiles.insert(name, specs);
+        }
+        config.exclude = raw.exclude;
+        config.include = raw.include;
+
+        // fail early instead of on the first image
+        try!(config.thumbnails());
+        Ok(config)
+    }
+
+    /// The thumbnails to generate for the selected profile.
+    pub fn thumbnails(&self) -> Result<&[ThumbnailSpec], Error> {
+        self.profile_thumbnails(&self.profile)
+    }
+
+    /// The thumbnails to generate for the profile `name`.
+    pub fn profile_thumbnails(&self, name: &str) -> Result<&[ThumbnailSpec], Error> {
+        self.profiles.get(name)
+            .map(|specs| &specs[..])
+            .ok_or_else(|| Error::Config(format!("unknown profile '{}'", name)))
+    }
+}
+
+/// Settings of a `.thumbrs.toml` file, which apply to its directory and are
+/// inherited by all subdirectories (unless overridden again further down).
+///
+/// ```toml
+/// # scans: larger thumbnails, higher quality
+/// profile = "scans"
+/// quality = 92
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct DirConfig {
+    /// Skip the directory and all its subdirectories.
+    pub skip: bool,
+    /// Only extract metadata (`true`) or also generate thumbnails (`false`).
+    pub metadata_only: Option<bool>,
+    /// Generate the thumbnails of this profile of the global configuration.
+    pub profile: Option<String>,
+    /// Generate these thumbnails; takes precedence over `profile`.
+    pub thumbnails: Option<Vec<ThumbnailSpec>>,
+    /// Use this JPEG quality for all thumbnails.
+    pub quality: Option<u8>,
+    /// Patterns of files and directories to skip, relative to the directory
+    /// of the file, in addition to the inherited ones.
+    pub exclude: Vec<String>,
+}
+
+impl DirConfig {
+    pub fn load(path: &Path) -> Result<DirConfig, Error> {
+        let raw: RawDirConfig = try!(read_config_file(path));
+
+        let thumbnails = match raw.thumbnails {
+            Some(specs) => Some(try!(specs.into_iter().map(ThumbnailSpec::from_raw).collect())),
+            None => None
+        };
+
+        Ok(DirConfig {
+            skip: raw.skip.unwrap_or(false),
+            metadata_only: raw.metadata_only,
+            profile: raw.profile,
+            thumbnails: thumbnails,
+            quality: match raw.quality {
+                Some(quality) => Some(try!(check_quality(quality))),
+                None => None
+            },
+            exclude: raw.exclude
+        })
+    }
+}
+
+fn check_quality(quality: u8) -> Result<u8, Error> {
+    if quality > 100 {
+        return Err(Error::Config(format!("quality {} out of range, expected 0-100", quality)));
+    }
+    Ok(quality)
+}
+
+// Files ending in `.json` are parsed as JSON, everything else as TOML.
+fn read_config_file<T: Deserialize>(path: &Path) -> Result<T, Error> {
+    let mut contents = String::new();
+    try!(File::open(path).and_then(|mut f| f.read_to_string(&mut contents)));
+
+    let is_json = path.extension().map_or(false, |e| e == "json");
+    match is_json {
+        true => parse_json(&contents),
+        false => parse_toml(&contents)
+    }.map_err(|msg| Error::Config(format!("{}: {}", path.display(), msg)))
+}
+
+fn parse_json<T: Deserialize>(contents: &str) -> Result<T, String> {
+    serde_json::from_str(contents).map_err(|err| err.to_string())
+}
+
+fn parse_toml<T: Deserialize>(contents: &str) -> Result<T, String> {
+    let mut parser = toml::Parser::new(contents);
+    let table = match parser.parse() {
+        Some(table) => table,
+        None => {
+            let msgs = parser.errors.iter().map(|e| e.to_string()).collect::<Vec<_>>();
+            return Err(msgs.join("; "));
+        }
+    };
+    let mut decoder = toml::Decoder::new(toml::Value::Table(table));
+    T::deserialize(&mut decoder).map_err(|err| err.to_string())
+}
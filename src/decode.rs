@@ -0,0 +1,263 @@
+//! Decoding of all supported input formats: JPEG is decoded with mozjpeg,
+//! RAW files are represented by their largest embedded preview, everything
+//! else is decoded with the `image` crate.
+
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::{Path, PathBuf};
+
+use image;
+use image::{DynamicImage, GenericImage, ImageDecoder};
+use rexiv2;
+use rexiv2::Orientation;
+
+use error::Error;
+use format::{FileFormat, detect_format, sniff_format};
+use jpegimpex::{read_jpeg, read_jpeg_from_slice, read_jpeg_from_slice_with_profile, read_jpeg_header, read_jpeg_icc_profile, read_jpeg_scaled, read_jpeg_size};
+
+impl FileFormat {
+    /// Whether files of this format can carry EXIF/XMP metadata that rexiv2
+    /// understands.
+    pub fn has_metadata(&self) -> bool {
+        match *self {
+            FileFormat::Jpeg | FileFormat::Png | FileFormat::Tiff | FileFormat::WebP | FileFormat::Raw => true,
+            FileFormat::Gif | FileFormat::Bmp => false,
+        }
+    }
+}
+
+/// Detects the format of `path`, failing for files that are not images.
+pub fn image_format(path: &Path) -> Result<FileFormat, Error> {
+    match try!(detect_format(path)) {
+        Some(format) => Ok(format),
+        None => Err(Error::UnknownFormat(path.to_path_buf()))
+    }
+}
+
+fn open(path: &Path) -> Result<BufReader<File>, Error> {
+    Ok(BufReader::new(try!(File::open(path))))
+}
+
+fn load(path: &Path, format: image::ImageFormat) -> Result<DynamicImage, Error> {
+    image::load(try!(open(path)), format).map_err(Error::Image)
+}
+
+/// Reads the EXIF/XMP metadata of the file at `path`.
+pub fn read_exif(path: &Path) -> Result<rexiv2::Metadata, Error> {
+    match path.to_str() {
+        Some(path_str) => rexiv2::Metadata::new_from_path(path_str).map_err(Error::Metadata),
+        // rexiv2 only opens unicode paths, so read other files ourselves
+        None => {
+            let mut data = Vec::new();
+            try!(File::open(path).and_then(|mut f| f.read_to_end(&mut data)));
+            rexiv2::Metadata::new_from_buffer(&data).map_err(Error::Metadata)
+        }
+    }
+}
+
+/// What is known about an image file before decoding it. It is read once
+/// per file and shared by the metadata extraction and the decoding of the
+/// image and its previews, which would otherwise each open the file again.
+pub struct ImageHeader {
+    pub path: PathBuf,
+    pub format: FileFormat,
+    /// Dimensions of the image as stored; for RAW files, of the sensor image.
+    pub size: (u32, u32),
+    /// The EXIF/XMP metadata; `None` for formats without any, or if it
+    /// could not be read.
+    pub exif: Option<rexiv2::Metadata>,
+    /// The embedded ICC profile, which also applies to the previews embedded
+    /// in the file. Only profiles embedded in JPEGs are currently read.
+    pub icc_profile: Option<Vec<u8>>,
+}
+
+impl ImageHeader {
+    /// Reads the header of the image at `path`, which is known to be in
+    /// `format`. Unreadable metadata is only fatal for RAW files, whose size
+    /// is taken from it.
+    pub fn read(path: &Path, format: FileFormat) -> Result<ImageHeader, Error> {
+        let exif = match format.has_metadata() {
+            true => match read_exif(path) {
+                Ok(exif) => Some(exif),
+                Err(err) => {
+                    if format == FileFormat::Raw {
+                        return Err(err);
+                    }
+                    warn!("could not read the metadata of '{}': {}", path.display(), err);
+                    None
+                }
+            },
+            false => None
+        };
+
+        let (size, icc_profile) = match (format, exif.as_ref()) {
+            (FileFormat::Jpeg, _) => try!(read_jpeg_header(path).map_err(Error::Decode)),
+            (FileFormat::Raw, Some(exif)) => ((exif.get_pixel_width() as u32, exif.get_pixel_height() as u32), None),
+            _ => (try!(read_image_size(path, format)), None)
+        };
+
+        Ok(ImageHeader {
+            path: path.to_path_buf(),
+            format: format,
+            size: size,
+            exif: exif,
+            icc_profile: icc_profile
+        })
+    }
+
+    /// The EXIF orientation of the image, if it has metadata.
+    pub fn orientation(&self) -> Option<Orientation> {
+        self.exif.as_ref().map(|exif| exif.get_orientation())
+    }
+
+    fn icc_profile(&self) -> Option<&[u8]> {
+        self.icc_profile.as_ref().map(|icc| &icc[..])
+    }
+}
+
+/// Decodes the largest preview image embedded in the file at `path`, as
+/// found in RAW files (and many camera JPEGs).
+pub fn read_largest_preview(path: &Path) -> Result<DynamicImage, Error> {
+    let exif = try!(read_exif(path));
+    decode_largest_preview(path, &exif, parent_icc_profile(path).as_ref().map(|icc| &icc[..]))
+}
+
+fn decode_largest_preview(path: &Path, exif: &rexiv2::Metadata, icc: Option<&[u8]>) -> Result<DynamicImage, Error> {
+    let previews = exif.get_preview_images().unwrap_or(Vec::new());
+    let largest = try!(previews.iter()
+        .max_by_key(|p| p.get_width() as u64 * p.get_height() as u64)
+        .ok_or_else(|| Error::NoPreview(path.to_path_buf())));
+
+    let data = try!(largest.get_data().map_err(Error::Metadata));
+    read_preview_data(&data, icc)
+}
+
+/// Decodes an image held in memory; JPEGs are decoded with mozjpeg, other
+/// formats with the `image` crate.
+pub fn read_image_from_slice(data: &[u8]) -> Result<DynamicImage, Error> {
+    match sniff_format(data) {
+        Some(FileFormat::Jpeg) => read_jpeg_from_slice(data).map_err(Error::Decode),
+        _ => image::load_from_memory(data).map_err(Error::Image)
+    }
+}
+
+// The ICC profile of the file at `path`, which applies to its embedded
+// previews as well. Only profiles embedded in JPEGs are currently read.
+fn parent_icc_profile(path: &Path) -> Option<Vec<u8>> {
+    match detect_format(path) {
+        Ok(Some(FileFormat::Jpeg)) => (),
+        _ => return None
+    }
+    match read_jpeg_icc_profile(path) {
+        Ok(icc) => icc,
+        Err(err) => {
+            warn!("could not read the ICC profile of '{}': {}", path.display(), err);
+            None
+        }
+    }
+}
+
+// Decodes a preview; JPEG previews without a profile of their own are
+// converted with `icc`, the profile of the file they are embedded in.
+fn read_preview_data(data: &[u8], icc: Option<&[u8]>) -> Result<DynamicImage, Error> {
+    match sniff_format(data) {
+        Some(FileFormat::Jpeg) => read_jpeg_from_slice_with_profile(data, icc).map_err(Error::Decode),
+        _ => read_image_from_slice(data)
+    }
+}
+
+// How much the aspect ratio of a preview may differ from the full image, e.g.
+// 160x120 EXIF thumbnails of 3:2 photos are letterboxed.
+const PREVIEW_ASPECT_TOLERANCE: f64 = 0.01;
+
+fn same_aspect(w: u32, h: u32, size: (u32, u32)) -> bool {
+    if h == 0 || size.1 == 0 {
+        return false;
+    }
+    let aspect = size.0 as f64 / size.1 as f64;
+    (w as f64 / h as f64 / aspect - 1.0).abs() <= PREVIEW_ASPECT_TOLERANCE
+}
+
+/// Decodes the smallest preview embedded in the file of `header` (EXIF
+/// thumbnail or larger preview) that is at least `min_w` x `min_h` and
+/// whose aspect ratio matches the full image. `Ok(None)` if there is no such
+/// preview.
+pub fn read_preview(header: &ImageHeader, min_w: u32, min_h: u32) -> Result<Option<DynamicImage>, Error> {
+    let previews = match header.exif {
+        Some(ref exif) => exif.get_preview_images().unwrap_or(Vec::new()),
+        None => return Ok(None)
+    };
+    let preview = previews.iter()
+        .filter(|p| p.get_width() >= min_w && p.get_height() >= min_h)
+        .filter(|p| same_aspect(p.get_width(), p.get_height(), header.size))
+        .min_by_key(|p| p.get_width() as u64 * p.get_height() as u64);
+
+    let preview = match preview {
+        Some(preview) => preview,
+        None => return Ok(None)
+    };
+    let data = try!(preview.get_data().map_err(Error::Metadata));
+    let img = try!(read_preview_data(&data, header.icc_profile()));
+
+    // do not trust the dimensions reported in the metadata
+    if img.width() < min_w || img.height() < min_h || !same_aspect(img.width(), img.height(), header.size) {
+        return Ok(None);
+    }
+    Ok(Some(img))
+}
+
+/// Decodes the image of `header`, possibly at a reduced scale that still
+/// covers `min_size`, like `read_image_scaled` and `read_image`, but without
+/// reading the metadata of RAW files again.
+pub fn decode_image(header: &ImageHeader, min_size: Option<(u32, u32)>) -> Result<DynamicImage, Error> {
+    match (header.format, min_size) {
+        (FileFormat::Raw, _) => match header.exif {
+            Some(ref exif) => decode_largest_preview(&header.path, exif, header.icc_profile()),
+            None => Err(Error::NoPreview(header.path.clone()))
+        },
+        (format, Some((min_w, min_h))) => read_image_scaled(&header.path, format, min_w, min_h),
+        (format, None) => read_image(&header.path, format)
+    }
+}
+
+/// Decodes the image at `path`.
+pub fn read_image(path: &Path, format: FileFormat) -> Result<DynamicImage, Error> {
+    match format {
+        FileFormat::Jpeg => read_jpeg(path).map_err(Error::Decode),
+        FileFormat::Raw => read_largest_preview(path),
+        FileFormat::Png => load(path, image::ImageFormat::PNG),
+        FileFormat::Gif => load(path, image::ImageFormat::GIF),
+        FileFormat::Tiff => load(path, image::ImageFormat::TIFF),
+        FileFormat::WebP => load(path, image::ImageFormat::WEBP),
+        FileFormat::Bmp => load(path, image::ImageFormat::BMP),
+    }
+}
+
+/// Decodes the image at `path`, possibly at a reduced scale that still
+/// covers `min_w` x `min_h`. Only JPEGs are currently decoded at a reduced
+/// scale.
+pub fn read_image_scaled(path: &Path, format: FileFormat, min_w: u32, min_h: u32) -> Result<DynamicImage, Error> {
+    match format {
+        FileFormat::Jpeg => read_jpeg_scaled(path, min_w, min_h).map_err(Error::Decode),
+        _ => read_image(path, format)
+    }
+}
+
+/// Reads the dimensions of the image at `path` from its header.
+pub fn read_image_size(path: &Path, format: FileFormat) -> Result<(u32, u32), Error> {
+    let size = match format {
+        FileFormat::Jpeg => return read_jpeg_size(path).map_err(Error::Decode),
+        FileFormat::Png => image::png::PNGDecoder::new(try!(open(path))).dimensions(),
+        FileFormat::Gif => image::gif::Decoder::new(try!(open(path))).dimensions(),
+        FileFormat::Tiff => image::tiff::TIFFDecoder::new(try!(open(path)))
+            .and_then(|mut decoder| decoder.dimensions()),
+        FileFormat::WebP => image::webp::WebpDecoder::new(try!(open(path))).dimensions(),
+        FileFormat::Bmp => image::bmp::BMPDecoder::new(try!(open(path))).dimensions(),
+        // size of the sensor image, not of the preview
+        FileFormat::Raw => {
+            let exif = try!(read_exif(path));
+            return Ok((exif.get_pixel_width() as u32, exif.get_pixel_height() as u32));
+        }
+    };
+    size.map_err(Error::Image)
+}
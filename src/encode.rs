@@ -0,0 +1,325 @@
+//! Encoding of thumbnails in all supported output formats: JPEG is encoded
+//! with mozjpeg, WebP with libwebp, AVIF with ravif (only when built with the
+//! `avif` feature) and PNG with the `image` crate.
+
+use std::cmp;
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::slice;
+
+use image::{self, DynamicImage, GenericImage, GrayImage, ImageLumaA8, ImageRgba8};
+use libc::{c_int, c_void};
+use libwebp_sys::{WebPEncodeLosslessRGB, WebPEncodeLosslessRGBA, WebPEncodeRGB, WebPEncodeRGBA, WebPFree};
+
+use config::ThumbnailSpec;
+use error::Error;
+use jpegimpex::{encode_jpeg_to_vec, read_jpeg_from_slice};
+use metadata::OutputFormat;
+
+impl OutputFormat {
+    /// Extension of thumbnail files in this format.
+    pub fn extension(&self) -> &'static str {
+        match *self {
+            OutputFormat::Jpeg => "jpg",
+            OutputFormat::WebP | OutputFormat::WebPLossless => "webp",
+            OutputFormat::Avif => "avif",
+            OutputFormat::Png => "png",
+        }
+    }
+}
+
+/// Chooses the quality of a thumbnail, instead of using a fixed one.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum QualityTarget {
+    /// The highest quality whose file has at most this many bytes.
+    Bytes(u64),
+    /// The lowest quality whose SSIM to the uncompressed thumbnail is at
+    /// least this value (0-1, e.g. 0.98).
+    Ssim(f64),
+}
+
+// Lower bound of the quality search; below this, JPEGs get blocky enough to
+// rather exceed a byte budget.
+const MIN_SEARCH_QUALITY: u8 = 20;
+
+/// Writes `img` to `path` in the format and with the encoder settings of
+/// `spec`; its `quality` (0-100) is ignored by the lossless formats. Returns
+/// the quality used, which differs from `spec.quality` if `spec.target` is
+/// set.
+pub fn write_image(img: &DynamicImage, path: &Path, spec: &ThumbnailSpec) -> Result<Option<u8>, Error> {
+    let (quality, data) = try!(encode_image(img, spec));
+    let mut f = try!(File::create(path));
+    try!(f.write_all(&data));
+    Ok(quality)
+}
+
+/// Like `write_image`, but returns the encoded file instead of writing it.
+pub fn encode_image(img: &DynamicImage, spec: &ThumbnailSpec) -> Result<(Option<u8>, Vec<u8>), Error> {
+    Ok(match spec.format {
+        OutputFormat::Jpeg => match spec.target {
+            Some(target) => {
+                let (quality, data) = try!(search_jpeg_quality(img, spec, target));
+                (Some(quality), data)
+            },
+            None => (Some(spec.quality), try!(encode_jpeg_to_vec(img, spec.quality, &spec.jpeg).map_err(Error::Encode)))
+        },
+        OutputFormat::Png => (None, try!(encode_png(img))),
+        OutputFormat::WebP => (Some(spec.quality), try!(encode_webp(img, spec.format, spec.quality))),
+        OutputFormat::WebPLossless => (None, try!(encode_webp(img, spec.format, spec.quality))),
+        OutputFormat::Avif => (Some(spec.quality), try!(encode_avif(img, spec.quality))),
+    })
+}
+
+// Binary search, in memory, for the quality meeting `target`. `spec.quality`
+// is the highest quality considered.
+fn search_jpeg_quality(img: &DynamicImage, spec: &ThumbnailSpec, target: QualityTarget) -> Result<(u8, Vec<u8>), Error> {
+    let reference = match target {
+        QualityTarget::Ssim(_) => Some(img.to_luma()),
+        QualityTarget::Bytes(_) => None
+    };
+    let encode = |quality| encode_jpeg_to_vec(img, quality, &spec.jpeg).map_err(Error::Encode);
+
+    let (mut lo, mut hi) = (cmp::min(MIN_SEARCH_QUALITY, spec.quality), spec.quality);
+    let mut found = None;
+    while lo <= hi {
+        let quality = lo + (hi - lo) / 2;
+        let data = try!(encode(quality));
+        let meets_target = match target {
+            QualityTarget::Bytes(max_bytes) => data.len() as u64 <= max_bytes,
+            QualityTarget::Ssim(min_ssim) => {
+                let decoded = try!(read_jpeg_from_slice(&data).map_err(Error::Decode));
+                ssim(reference.as_ref().unwrap(), &decoded.to_luma()) >= min_ssim
+            }
+        };
+        // file size and SSIM both grow with the quality
+        let search_lower = match target {
+            QualityTarget::Bytes(_) => !meets_target,
+            QualityTarget::Ssim(_) => meets_target
+        };
+
+        if meets_target {
+            found = Some((quality, data));
+        }
+        if search_lower {
+            match quality.checked_sub(1) {
+                Some(q) => hi = q,
+                None => break
+            }
+        } else {
+            match quality.checked_add(1) {
+                Some(q) => lo = q,
+                None => break
+            }
+        }
+    }
+
+    match found {
+        Some(found) => Ok(found),
+        None => {
+            // the budget cannot be met: use the smallest file, or the target
+            // SSIM cannot be reached: use the best quality
+            let quality = match target {
+                QualityTarget::Bytes(_) => cmp::min(MIN_SEARCH_QUALITY, spec.quality),
+                QualityTarget::Ssim(_) => spec.quality
+            };
+            Ok((quality, try!(encode(quality))))
+        }
+    }
+}
+
+// Side of the windows over which the SSIM is computed.
+const SSIM_WINDOW: u32 = 8;
+
+// Mean structural similarity of two equally sized grayscale images, computed
+// over overlapping 8x8 windows (without the usual Gaussian weighting).
+fn ssim(a: &GrayImage, b: &GrayImage) -> f64 {
+    const C1: f64 = (0.01 * 255.0) * (0.01 * 255.0);
+    const C2: f64 = (0.03 * 255.0) * (0.03 * 255.0);
+
+    let (width, height) = a.dimensions();
+    let window = cmp::min(SSIM_WINDOW, cmp::min(width, height));
+    if window == 0 {
+        return 1.0;
+    }
+    let step = cmp::max(1, window / 2);
+
+    let (mut total, mut count) = (0.0, 0);
+    let mut y = 0;
+    while y + window <= height {
+        let mut x = 0;
+        while x + window <= width {
+            let (mut sum_a, mut sum_b, mut sum_aa, mut sum_bb, mut sum_ab) = (0.0, 0.0, 0.0, 0.0, 0.0);
+            for wy in y..y + window {
+                for wx in x..x + window {
+                    let pa = a.get_pixel(wx, wy).data[0] as f64;
+                    let pb = b.get_pixel(wx, wy).data[0] as f64;
+                    sum_a += pa;
+                    sum_b += pb;
+                    sum_aa += pa * pa;
+                    sum_bb += pb * pb;
+                    sum_ab += pa * pb;
+                }
+            }
+
+            let n = (window * window) as f64;
+            let (mean_a, mean_b) = (sum_a / n, sum_b / n);
+            let var_a = sum_aa / n - mean_a * mean_a;
+            let var_b = sum_bb / n - mean_b * mean_b;
+            let cov = sum_ab / n - mean_a * mean_b;
+            total += ((2.0 * mean_a * mean_b + C1) * (2.0 * cov + C2))
+                / ((mean_a * mean_a + mean_b * mean_b + C1) * (var_a + var_b + C2));
+            count += 1;
+            x += step;
+        }
+        y += step;
+    }
+    total / count as f64
+}
+
+// Whether `img` has any pixels that are not fully opaque; only then is alpha
+// kept by the formats supporting it.
+fn has_transparency(img: &DynamicImage) -> bool {
+    match *img {
+        ImageLumaA8(ref buf) => buf.pixels().any(|px| px.data[1] < 255),
+        ImageRgba8(ref buf) => buf.pixels().any(|px| px.data[3] < 255),
+        _ => false
+    }
+}
+
+fn encode_png(img: &DynamicImage) -> Result<Vec<u8>, Error> {
+    let (width, height) = img.dimensions();
+    let mut data = Vec::new();
+    {
+        let encoder = image::png::PNGEncoder::new(&mut data);
+        try!(match has_transparency(img) {
+            true => encoder.encode(&img.to_rgba(), width, height, image::ColorType::RGBA(8)),
+            false => encoder.encode(&img.to_rgb(), width, height, image::ColorType::RGB(8))
+        });
+    }
+    Ok(data)
+}
+
+fn encode_webp(img: &DynamicImage, format: OutputFormat, quality: u8) -> Result<Vec<u8>, Error> {
+    let (width, height) = img.dimensions();
+    let (w, h) = (width as c_int, height as c_int);
+    let alpha = has_transparency(img);
+    let pixels = match alpha {
+        true => img.to_rgba().into_raw(),
+        false => img.to_rgb().into_raw()
+    };
+
+    unsafe {
+        let mut output: *mut u8 = 0 as *mut u8;
+        let len = match (format, alpha) {
+            (OutputFormat::WebPLossless, true) => WebPEncodeLosslessRGBA(pixels.as_ptr(), w, h, w * 4, &mut output),
+            (OutputFormat::WebPLossless, false) => WebPEncodeLosslessRGB(pixels.as_ptr(), w, h, w * 3, &mut output),
+            (_, true) => WebPEncodeRGBA(pixels.as_ptr(), w, h, w * 4, quality as f32, &mut output),
+            (_, false) => WebPEncodeRGB(pixels.as_ptr(), w, h, w * 3, quality as f32, &mut output),
+        };
+        // libwebp does not report why encoding failed
+        if len == 0 || output.is_null() {
+            return Err(Error::EncodeFormat(format, "libwebp could not encode the image".to_string()));
+        }
+
+        let data = slice::from_raw_parts(output, len as usize).to_vec();
+        WebPFree(output as *mut c_void);
+        Ok(data)
+    }
+}
+
+#[cfg(feature = "avif")]
+fn encode_avif(img: &DynamicImage, quality: u8) -> Result<Vec<u8>, Error> {
+    use imgref::Img;
+    use ravif::Encoder;
+    use rgb::FromSlice;
+
+    let rgb = img.to_rgb();
+    let (width, height) = rgb.dimensions();
+    let pixels = Img::new(rgb[..].as_rgb(), width as usize, height as usize);
+
+    Encoder::new()
+        .with_quality(quality as f32)
+        .with_speed(6)
+        .encode_rgb(pixels)
+        .map(|encoded| encoded.avif_file)
+        .map_err(|err| Error::EncodeFormat(OutputFormat::Avif, err.to_string()))
+}
+
+#[cfg(not(feature = "avif"))]
+fn encode_avif(_: &DynamicImage, _: u8) -> Result<Vec<u8>, Error> {
+    Err(Error::EncodeFormat(OutputFormat::Avif, "built without the `avif` feature".to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{DynamicImage, ImageBuffer, ImageRgb8, Rgb};
+
+    use config::ThumbnailSpec;
+    use jpegimpex::encode_jpeg_to_vec;
+
+    use super::*;
+
+    // Detailed enough for the file size to grow noticeably with the quality.
+    fn test_image() -> DynamicImage {
+        ImageRgb8(ImageBuffer::from_fn(64, 64, |x, y| {
+            Rgb([(x * 4) as u8, (y * 4) as u8, ((x * 7 + y * 13) % 64 * 4) as u8])
+        }))
+    }
+
+    fn encoded_len(img: &DynamicImage, spec: &ThumbnailSpec, quality: u8) -> u64 {
+        encode_jpeg_to_vec(img, quality, &spec.jpeg).unwrap().len() as u64
+    }
+
+    #[test]
+    fn byte_budget_is_not_exceeded() {
+        let img = test_image();
+        let spec = ThumbnailSpec::new(64, 90);
+        let budget = (encoded_len(&img, &spec, MIN_SEARCH_QUALITY) + encoded_len(&img, &spec, 90)) / 2;
+
+        let (quality, data) = search_jpeg_quality(&img, &spec, QualityTarget::Bytes(budget)).unwrap();
+        assert!(data.len() as u64 <= budget);
+        assert!(quality >= MIN_SEARCH_QUALITY && quality < 90);
+        assert_eq!(data.len() as u64, encoded_len(&img, &spec, quality));
+    }
+
+    #[test]
+    fn ample_byte_budget_gives_highest_quality() {
+        let img = test_image();
+        let spec = ThumbnailSpec::new(64, 90);
+        let budget = encoded_len(&img, &spec, 90);
+
+        let (quality, _) = search_jpeg_quality(&img, &spec, QualityTarget::Bytes(budget)).unwrap();
+        assert_eq!(quality, 90);
+    }
+
+    #[test]
+    fn unreachable_byte_budget_falls_back_to_lowest_quality() {
+        let img = test_image();
+        let spec = ThumbnailSpec::new(64, 90);
+        let (quality, data) = search_jpeg_quality(&img, &spec, QualityTarget::Bytes(1)).unwrap();
+        assert_eq!(quality, MIN_SEARCH_QUALITY);
+        assert_eq!(data.len() as u64, encoded_len(&img, &spec, MIN_SEARCH_QUALITY));
+
+        // never above the configured quality
+        let spec = ThumbnailSpec::new(64, 10);
+        let (quality, _) = search_jpeg_quality(&img, &spec, QualityTarget::Bytes(1)).unwrap();
+        assert_eq!(quality, 10);
+    }
+
+    #[test]
+    fn unreachable_ssim_falls_back_to_highest_quality() {
+        let img = test_image();
+        let spec = ThumbnailSpec::new(64, 90);
+        let (quality, _) = search_jpeg_quality(&img, &spec, QualityTarget::Ssim(1.1)).unwrap();
+        assert_eq!(quality, 90);
+    }
+
+    #[test]
+    fn trivial_ssim_gives_lowest_quality() {
+        let img = test_image();
+        let spec = ThumbnailSpec::new(64, 90);
+        let (quality, _) = search_jpeg_quality(&img, &spec, QualityTarget::Ssim(0.0)).unwrap();
+        assert_eq!(quality, MIN_SEARCH_QUALITY);
+    }
+}
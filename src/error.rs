@@ -0,0 +1,105 @@
+use std::error;
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+use image;
+use rexiv2;
+
+use jpegimpex::JpegError;
+use metadata::OutputFormat;
+
+/// Errors returned by the thumbnail and metadata functions of this crate.
+#[derive(Debug)]
+pub enum Error {
+    /// Reading or writing a file failed.
+    Io(io::Error),
+    /// The source image could not be decoded.
+    Decode(JpegError),
+    /// The source image (in a format other than JPEG) could not be decoded.
+    Image(image::ImageError),
+    /// The file is not in any of the supported image formats.
+    UnknownFormat(PathBuf),
+    /// The RAW file does not contain a preview image.
+    NoPreview(PathBuf),
+    /// A thumbnail could not be encoded or written.
+    Encode(JpegError),
+    /// A thumbnail could not be encoded in a format other than JPEG.
+    EncodeFormat(OutputFormat, String),
+    /// The EXIF/XMP metadata of the image could not be read.
+    Metadata(rexiv2::Rexiv2Error),
+    /// The path lacks a file name or cannot be passed to a C library.
+    Path(PathBuf),
+    /// A configuration file is malformed.
+    Config(String),
+}
+
+impl Error {
+    /// Whether the error means that the input file is damaged, as opposed
+    /// to valid but unsupported or not readable at all.
+    pub fn is_damaged_input(&self) -> bool {
+        match *self {
+            Error::Decode(JpegError::Corrupt(_)) => true,
+            Error::Image(image::ImageError::FormatError(_)) |
+            Error::Image(image::ImageError::NotEnoughData) |
+            Error::Image(image::ImageError::ImageEnd) => true,
+            _ => false
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Io(ref err) => write!(f, "I/O error: {}", err),
+            Error::Decode(ref err) => write!(f, "decoding failed: {}", err),
+            Error::Image(ref err) => write!(f, "decoding failed: {}", err),
+            Error::UnknownFormat(ref path) => write!(f, "'{}' is not in a supported image format", path.display()),
+            Error::NoPreview(ref path) => write!(f, "'{}' contains no preview image", path.display()),
+            Error::Encode(ref err) => write!(f, "encoding failed: {}", err),
+            Error::EncodeFormat(ref format, ref msg) => write!(f, "encoding {:?} failed: {}", format, msg),
+            Error::Metadata(ref err) => write!(f, "could not read metadata: {}", err),
+            Error::Path(ref path) => write!(f, "unsupported path '{}'", path.display()),
+            Error::Config(ref msg) => write!(f, "invalid configuration: {}", msg),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn description(&self) -> &str {
+        match *self {
+            Error::Io(_) => "I/O error",
+            Error::Decode(_) => "decoding failed",
+            Error::Image(_) => "decoding failed",
+            Error::UnknownFormat(_) => "unsupported image format",
+            Error::NoPreview(_) => "no preview image",
+            Error::Encode(_) => "encoding failed",
+            Error::EncodeFormat(..) => "encoding failed",
+            Error::Metadata(_) => "could not read metadata",
+            Error::Path(_) => "unsupported path",
+            Error::Config(_) => "invalid configuration",
+        }
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        match *self {
+            Error::Io(ref err) => Some(err),
+            Error::Decode(ref err) => Some(err),
+            Error::Image(ref err) => Some(err),
+            Error::Encode(ref err) => Some(err),
+            _ => None
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::Io(err)
+    }
+}
+
+impl From<rexiv2::Rexiv2Error> for Error {
+    fn from(err: rexiv2::Rexiv2Error) -> Error {
+        Error::Metadata(err)
+    }
+}
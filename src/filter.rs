@@ -0,0 +1,261 @@
+//! gitignore-style selection of the files and directories to process.
+//!
+//! Exclude patterns follow the rules of `.gitignore` files:
+//!
+//! - a pattern without a `/` matches file or directory names at any depth
+//!   below the directory it was defined in (`*.tmp.jpg`, `Thumbs`)
+//! - any other pattern is matched against the path relative to that
+//!   directory; `*` does not match `/`, `**` matches any number of
+//!   directories (`*/export/*`, `**/Thumbs`)
+//! - a trailing `/` only matches directories
+//! - a leading `!` re-includes paths excluded by an earlier pattern
+//!
+//! Patterns come from the command line and configuration (relative to the
+//! input root) and from `.thumbrsignore` files (relative to their directory).
+//!
+//! Include patterns are relative to the input root and match names the same
+//! way: `*.jpg` selects JPEGs at any depth, `2016/*.jpg` only those directly
+//! in `2016`.
+
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use glob::{MatchOptions, Pattern};
+
+use error::Error;
+
+/// Name of the per-directory ignore file.
+pub const IGNORE_FILE: &'static str = ".thumbrsignore";
+
+#[derive(Debug, Clone)]
+struct Rule {
+    // directory the pattern is relative to, relative to the input root
+    base: PathBuf,
+    pattern: Pattern,
+    // matched against the file name only
+    basename: bool,
+    dir_only: bool,
+    negated: bool,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PathFilter {
+    excludes: Vec<Rule>,
+    includes: Vec<Rule>,
+}
+
+const MATCH_OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: true,
+    require_literal_separator: true,
+    require_literal_leading_dot: false
+};
+
+fn parse_pattern(pattern: &str) -> Result<Pattern, Error> {
+    Pattern::new(pattern).map_err(|err| Error::Config(format!("invalid pattern '{}': {}", pattern, err.msg)))
+}
+
+// Joins the components of a relative path with `/`, independent of the
+// platform.
+fn to_slash_path(path: &Path) -> String {
+    path.components()
+        .map(|c| c.as_os_str().to_string_lossy().into_owned())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+impl Rule {
+    // Parses `pattern`, relative to the directory `base`.
+    fn new(base: &Path, pattern: &str) -> Result<Rule, Error> {
+        let (negated, pattern) = match pattern.starts_with('!') {
+            true => (true, &pattern[1..]),
+            false => (false, pattern)
+        };
+        let (dir_only, pattern) = match pattern.ends_with('/') {
+            true => (true, &pattern[..pattern.len() - 1]),
+            false => (false, pattern)
+        };
+        let basename = !pattern.contains('/');
+        let pattern = pattern.trim_left_matches('/');
+
+        Ok(Rule {
+            base: base.to_path_buf(),
+            pattern: try!(parse_pattern(pattern)),
+            basename: basename,
+            dir_only: dir_only,
+            negated: negated
+        })
+    }
+
+    // Whether the pattern matches `relpath` (relative to the input root),
+    // ignoring `dir_only` and `negated`.
+    fn matches(&self, relpath: &Path) -> bool {
+        if !relpath.starts_with(&self.base) {
+            return false;
+        }
+        match self.basename {
+            true => relpath.file_name()
+                .map_or(false, |name| self.pattern.matches(&name.to_string_lossy())),
+            false => {
+                let rel = relpath.components().skip(self.base.components().count()).collect::<PathBuf>();
+                self.pattern.matches_with(&to_slash_path(&rel), &MATCH_OPTIONS)
+            }
+        }
+    }
+}
+
+impl PathFilter {
+    pub fn new() -> PathFilter {
+        PathFilter::default()
+    }
+
+    /// Adds an exclude pattern, relative to the directory `base` (itself
+    /// relative to the input root).
+    pub fn add_exclude(&mut self, base: &Path, pattern: &str) -> Result<(), Error> {
+        self.excludes.push(try!(Rule::new(base, pattern)));
+        Ok(())
+    }
+
+    /// Adds an include pattern, relative to the input root. Once there is at
+    /// least one, only files matching any of them are processed.
+    pub fn add_include(&mut self, pattern: &str) -> Result<(), Error> {
+        let rule = try!(Rule::new(Path::new(""), pattern));
+        if rule.negated || rule.dir_only {
+            return Err(Error::Config(format!("include pattern '{}' cannot be negated or match directories only", pattern)));
+        }
+        self.includes.push(rule);
+        Ok(())
+    }
+
+    /// Adds the patterns of an ignore file in the directory `base`; empty
+    /// lines and lines starting with `#` are skipped. Invalid patterns are
+    /// skipped as well, and returned with their line numbers.
+    pub fn add_ignore_file(&mut self, base: &Path, file: &Path) -> Result<Vec<(usize, Error)>, Error> {
+        let mut contents = String::new();
+        try!(File::open(file).and_then(|mut f| f.read_to_string(&mut contents)));
+
+        let mut invalid = Vec::new();
+        for (i, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if let Err(err) = self.add_exclude(base, line) {
+                invalid.push((i + 1, err));
+            }
+        }
+        Ok(invalid)
+    }
+
+    /// Whether `relpath` (relative to the input root) is excluded.
+    pub fn is_excluded(&self, relpath: &Path, is_dir: bool) -> bool {
+        let mut excluded = false;
+        // as in gitignore, the last matching pattern wins
+        for rule in self.excludes.iter() {
+            if excluded != rule.negated || (rule.dir_only && !is_dir) {
+                continue;
+            }
+            if rule.matches(relpath) {
+                excluded = !rule.negated;
+            }
+        }
+        excluded
+    }
+
+    /// Whether the file `relpath` (relative to the input root) matches the
+    /// include patterns.
+    pub fn is_included(&self, relpath: &Path) -> bool {
+        self.includes.is_empty() || self.includes.iter().any(|rule| rule.matches(relpath))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+
+    fn excludes(patterns: &[&str]) -> PathFilter {
+        let mut filter = PathFilter::new();
+        for pattern in patterns {
+            filter.add_exclude(Path::new(""), pattern).unwrap();
+        }
+        filter
+    }
+
+    fn is_excluded(filter: &PathFilter, path: &str, is_dir: bool) -> bool {
+        filter.is_excluded(Path::new(path), is_dir)
+    }
+
+    #[test]
+    fn basename_patterns_match_at_any_depth() {
+        let filter = excludes(&["*.tmp.jpg"]);
+        assert!(is_excluded(&filter, "a.tmp.jpg", false));
+        assert!(is_excluded(&filter, "2016/summer/a.tmp.jpg", false));
+        assert!(!is_excluded(&filter, "2016/summer/a.jpg", false));
+    }
+
+    #[test]
+    fn last_matching_pattern_wins() {
+        let filter = excludes(&["*.jpg", "!keep*.jpg"]);
+        assert!(is_excluded(&filter, "a.jpg", false));
+        assert!(!is_excluded(&filter, "keep.jpg", false));
+
+        let filter = excludes(&["!keep*.jpg", "*.jpg"]);
+        assert!(is_excluded(&filter, "keep.jpg", false));
+    }
+
+    #[test]
+    fn trailing_slash_only_matches_directories() {
+        let filter = excludes(&["export/"]);
+        assert!(is_excluded(&filter, "export", true));
+        assert!(is_excluded(&filter, "2016/export", true));
+        assert!(!is_excluded(&filter, "export", false));
+    }
+
+    #[test]
+    fn leading_slash_anchors_to_the_base() {
+        let filter = excludes(&["/export"]);
+        assert!(is_excluded(&filter, "export", true));
+        assert!(!is_excluded(&filter, "2016/export", true));
+
+        let mut filter = PathFilter::new();
+        filter.add_exclude(Path::new("2016"), "/export").unwrap();
+        assert!(is_excluded(&filter, "2016/export", true));
+        assert!(!is_excluded(&filter, "export", true));
+        assert!(!is_excluded(&filter, "2016/summer/export", true));
+    }
+
+    #[test]
+    fn single_star_does_not_match_separators() {
+        let filter = excludes(&["2016/*.jpg"]);
+        assert!(is_excluded(&filter, "2016/a.jpg", false));
+        assert!(!is_excluded(&filter, "2016/summer/a.jpg", false));
+    }
+
+    #[test]
+    fn double_star_matches_any_number_of_directories() {
+        let filter = excludes(&["2016/**/Thumbs"]);
+        assert!(is_excluded(&filter, "2016/Thumbs", true));
+        assert!(is_excluded(&filter, "2016/summer/Thumbs", true));
+        assert!(is_excluded(&filter, "2016/summer/day1/Thumbs", true));
+        assert!(!is_excluded(&filter, "2017/Thumbs", true));
+    }
+
+    #[test]
+    fn includes_match_names_at_any_depth() {
+        let mut filter = PathFilter::new();
+        assert!(filter.is_included(Path::new("a.png")));
+
+        filter.add_include("*.jpg").unwrap();
+        assert!(filter.is_included(Path::new("a.jpg")));
+        assert!(filter.is_included(Path::new("2016/summer/a.jpg")));
+        assert!(!filter.is_included(Path::new("2016/a.png")));
+
+        let mut filter = PathFilter::new();
+        filter.add_include("2016/*.jpg").unwrap();
+        assert!(filter.is_included(Path::new("2016/a.jpg")));
+        assert!(!filter.is_included(Path::new("a.jpg")));
+        assert!(!filter.is_included(Path::new("2016/summer/a.jpg")));
+    }
+}
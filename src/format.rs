@@ -0,0 +1,154 @@
+//! Detection of image formats from file signatures ("magic bytes"), so that
+//! files are selected independently of their extension.
+
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+pub use metadata::FileFormat;
+
+// (offset, signature) pairs that all have to match; RAW formats come first,
+// as many of them are TIFF files
+const SIGNATURES: &'static [(FileFormat, &'static [(usize, &'static [u8])])] = &[
+    (FileFormat::Raw, &[(0, b"II*\x00"), (8, b"CR")]),
+    (FileFormat::Raw, &[(0, b"IIRO")]),
+    (FileFormat::Raw, &[(0, b"IIRS")]),
+    (FileFormat::Raw, &[(0, b"MMOR")]),
+    (FileFormat::Raw, &[(0, b"IIU\x00")]),
+    (FileFormat::Raw, &[(0, b"FUJIFILMCCD-RAW")]),
+    // Canon CRW (CIFF)
+    (FileFormat::Raw, &[(0, b"II\x1a\x00"), (6, b"HEAPCCDR")]),
+    // Minolta MRW
+    (FileFormat::Raw, &[(0, b"\x00MRM")]),
+    (FileFormat::Jpeg, &[(0, b"\xFF\xD8\xFF")]),
+    (FileFormat::Png, &[(0, b"\x89PNG\r\n\x1A\n")]),
+    (FileFormat::Gif, &[(0, b"GIF87a")]),
+    (FileFormat::Gif, &[(0, b"GIF89a")]),
+    (FileFormat::Tiff, &[(0, b"II*\x00")]),
+    (FileFormat::Tiff, &[(0, b"MM\x00*")]),
+    (FileFormat::WebP, &[(0, b"RIFF"), (8, b"WEBP")]),
+    (FileFormat::Bmp, &[(0, b"BM")]),
+];
+
+// Number of bytes needed to check all signatures.
+const HEADER_LEN: usize = 16;
+
+impl FileFormat {
+    /// Lower case extensions commonly used for this format.
+    pub fn extensions(&self) -> &'static [&'static str] {
+        match *self {
+            FileFormat::Jpeg => &["jpg", "jpeg", "jpe", "jfif"],
+            FileFormat::Png => &["png"],
+            FileFormat::Gif => &["gif"],
+            FileFormat::Tiff => &["tif", "tiff"],
+            FileFormat::WebP => &["webp"],
+            FileFormat::Bmp => &["bmp", "dib"],
+            // not Sigma's X3F, whose previews exiv2 cannot read
+            FileFormat::Raw => &["3fr", "arw", "cr2", "crw", "dng", "erf", "kdc", "mrw", "nef",
+                                 "nrw", "orf", "pef", "raf", "rw2", "sr2", "srf", "srw"],
+        }
+    }
+
+    /// Whether `path` has an extension commonly used for this format
+    /// (ignoring case).
+    pub fn matches_extension(&self, path: &Path) -> bool {
+        let ext = match path.extension().and_then(|e| e.to_str()) {
+            Some(ext) => ext.to_lowercase(),
+            None => return false
+        };
+        self.extensions().iter().any(|e| *e == ext)
+    }
+
+    /// The format whose extensions include the extension of `path`.
+    pub fn from_extension(path: &Path) -> Option<FileFormat> {
+        SIGNATURES.iter()
+            .map(|&(format, _)| format)
+            .find(|format| format.matches_extension(path))
+    }
+}
+
+/// Detects the format of an image from its first bytes.
+pub fn sniff_format(header: &[u8]) -> Option<FileFormat> {
+    SIGNATURES.iter()
+        .find(|&&(_, parts)| parts.iter().all(|&(offset, magic)| {
+            header.len() >= offset + magic.len() && &header[offset..offset + magic.len()] == magic
+        }))
+        .map(|&(format, _)| format)
+}
+
+/// Detects the format of the image file at `path` from its contents;
+/// `None` if it is not a known image format.
+///
+/// Many RAW formats (NEF, ARW, DNG, ...) are plain TIFF files; these are
+/// told apart from TIFF images by their extension.
+pub fn detect_format(path: &Path) -> io::Result<Option<FileFormat>> {
+    let mut header = Vec::with_capacity(HEADER_LEN);
+    let f = try!(File::open(path));
+    try!(f.take(HEADER_LEN as u64).read_to_end(&mut header));
+
+    Ok(match sniff_format(&header) {
+        Some(FileFormat::Tiff) if FileFormat::Raw.matches_extension(path) => Some(FileFormat::Raw),
+        format => format
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+
+    #[test]
+    fn sniffs_common_formats() {
+        assert_eq!(sniff_format(b"\xFF\xD8\xFF\xE0\x00\x10JFIF\x00"), Some(FileFormat::Jpeg));
+        assert_eq!(sniff_format(b"\x89PNG\r\n\x1A\n\x00\x00\x00\x0DIHDR"), Some(FileFormat::Png));
+        assert_eq!(sniff_format(b"GIF87a\x01\x00\x01\x00"), Some(FileFormat::Gif));
+        assert_eq!(sniff_format(b"GIF89a\x01\x00\x01\x00"), Some(FileFormat::Gif));
+        assert_eq!(sniff_format(b"RIFF\x24\x00\x00\x00WEBPVP8 "), Some(FileFormat::WebP));
+        assert_eq!(sniff_format(b"BM\x36\x00\x00\x00\x00\x00"), Some(FileFormat::Bmp));
+    }
+
+    #[test]
+    fn tells_canon_raw_from_tiff() {
+        assert_eq!(sniff_format(b"II*\x00\x10\x00\x00\x00CR\x02\x00"), Some(FileFormat::Raw));
+        assert_eq!(sniff_format(b"II*\x00\x08\x00\x00\x00\x0A\x00"), Some(FileFormat::Tiff));
+        assert_eq!(sniff_format(b"MM\x00*\x00\x00\x00\x08"), Some(FileFormat::Tiff));
+    }
+
+    #[test]
+    fn sniffs_raw_signatures() {
+        assert_eq!(sniff_format(b"II\x1a\x00\x00\x00HEAPCCDR"), Some(FileFormat::Raw));
+        assert_eq!(sniff_format(b"\x00MRM\x00\x00\x00\x00"), Some(FileFormat::Raw));
+        assert_eq!(sniff_format(b"FUJIFILMCCD-RAW 0201"), Some(FileFormat::Raw));
+        assert_eq!(sniff_format(b"IIRO\x08\x00\x00\x00"), Some(FileFormat::Raw));
+        assert_eq!(sniff_format(b"IIU\x00\x08\x00\x00\x00"), Some(FileFormat::Raw));
+    }
+
+    #[test]
+    fn unknown_and_short_headers() {
+        assert_eq!(sniff_format(b""), None);
+        assert_eq!(sniff_format(b"\xFF\xD8"), None);
+        assert_eq!(sniff_format(b"RIFF\x24\x00\x00\x00WAVE"), None);
+        assert_eq!(sniff_format(b"RIFF\x24\x00"), None);
+        assert_eq!(sniff_format(b"<?xml version=\"1.0\"?>"), None);
+        // a CRW header cut off before its signature
+        assert_eq!(sniff_format(b"II\x1a\x00\x00\x00HEAP"), None);
+    }
+
+    #[test]
+    fn header_covers_all_signatures() {
+        for &(_, parts) in SIGNATURES {
+            for &(offset, magic) in parts {
+                assert!(offset + magic.len() <= HEADER_LEN);
+            }
+        }
+    }
+
+    #[test]
+    fn matches_extensions_ignoring_case() {
+        assert!(FileFormat::Jpeg.matches_extension(Path::new("a/IMG_0001.JPG")));
+        assert!(!FileFormat::Jpeg.matches_extension(Path::new("a/IMG_0001")));
+        assert_eq!(FileFormat::from_extension(Path::new("IMG_0001.Nef")), Some(FileFormat::Raw));
+        assert_eq!(FileFormat::from_extension(Path::new("IMG_0001.x3f")), None);
+    }
+}
@@ -0,0 +1,167 @@
+/*
+ * Wrappers around the libjpeg calls that can fail.
+ *
+ * libjpeg reports fatal errors by calling the error manager's `error_exit`,
+ * which must not return. Unwinding a Rust panic out of it would cross C
+ * frames, which aborts the process, so `thumbrs_jpeg_error_exit` longjmps
+ * back into the wrapper that made the call instead. Each wrapper returns 1
+ * on success and 0 if libjpeg failed; the message can then be formatted from
+ * the error manager, which still holds the message code and parameters.
+ *
+ * Only C frames lie between a wrapper and `error_exit`: the Rust callbacks
+ * libjpeg calls (message output, destination managers) never call back into
+ * libjpeg.
+ *
+ * The libjpeg structs are only passed through, so they are declared as
+ * `void`; the layouts are those of the mozjpeg built by mozjpeg-sys.
+ */
+
+#include <setjmp.h>
+#include <stddef.h>
+#include <stdio.h>
+
+/* the jump buffer of the wrapper running on this thread */
+static _Thread_local jmp_buf *thumbrs_jpeg_jmp;
+
+void thumbrs_jpeg_error_exit(void *cinfo)
+{
+    (void)cinfo;
+    longjmp(*thumbrs_jpeg_jmp, 1);
+}
+
+/* for Rust, which declares `error_exit` as a safe function pointer */
+void (*const thumbrs_jpeg_error_exit_fn)(void *cinfo) = thumbrs_jpeg_error_exit;
+
+#define THUMBRS_TRY(call) do { \
+        jmp_buf buf; \
+        jmp_buf *prev = thumbrs_jpeg_jmp; \
+        thumbrs_jpeg_jmp = &buf; \
+        if (setjmp(buf)) { \
+            thumbrs_jpeg_jmp = prev; \
+            return 0; \
+        } \
+        call; \
+        thumbrs_jpeg_jmp = prev; \
+        return 1; \
+    } while (0)
+
+extern void jpeg_CreateDecompress(void *cinfo, int version, size_t structsize);
+extern void jpeg_CreateCompress(void *cinfo, int version, size_t structsize);
+extern void jpeg_stdio_src(void *cinfo, FILE *infile);
+extern void jpeg_mem_src(void *cinfo, const unsigned char *inbuffer, unsigned long insize);
+extern void jpeg_stdio_dest(void *cinfo, FILE *outfile);
+extern void jpeg_save_markers(void *cinfo, int marker_code, unsigned int length_limit);
+extern int jpeg_read_header(void *cinfo, int require_image);
+extern void jpeg_calc_output_dimensions(void *cinfo);
+extern int jpeg_start_decompress(void *cinfo);
+extern unsigned int jpeg_read_scanlines(void *cinfo, unsigned char **scanlines, unsigned int max_lines);
+extern int jpeg_finish_decompress(void *cinfo);
+extern void jpeg_set_defaults(void *cinfo);
+extern void jpeg_set_quality(void *cinfo, int quality, int force_baseline);
+extern void jpeg_simple_progression(void *cinfo);
+extern void jpeg_c_set_bool_param(void *cinfo, int param, int value);
+extern void jpeg_c_set_int_param(void *cinfo, int param, int value);
+extern void jpeg_start_compress(void *cinfo, int write_all_tables);
+extern void jpeg_write_marker(void *cinfo, int marker, const unsigned char *dataptr, unsigned int datalen);
+extern unsigned int jpeg_write_scanlines(void *cinfo, unsigned char **scanlines, unsigned int num_lines);
+extern void jpeg_finish_compress(void *cinfo);
+
+int thumbrs_jpeg_create_decompress(void *cinfo, int version, size_t structsize)
+{
+    THUMBRS_TRY(jpeg_CreateDecompress(cinfo, version, structsize));
+}
+
+int thumbrs_jpeg_create_compress(void *cinfo, int version, size_t structsize)
+{
+    THUMBRS_TRY(jpeg_CreateCompress(cinfo, version, structsize));
+}
+
+int thumbrs_jpeg_stdio_src(void *cinfo, FILE *infile)
+{
+    THUMBRS_TRY(jpeg_stdio_src(cinfo, infile));
+}
+
+int thumbrs_jpeg_mem_src(void *cinfo, const unsigned char *inbuffer, unsigned long insize)
+{
+    THUMBRS_TRY(jpeg_mem_src(cinfo, inbuffer, insize));
+}
+
+int thumbrs_jpeg_stdio_dest(void *cinfo, FILE *outfile)
+{
+    THUMBRS_TRY(jpeg_stdio_dest(cinfo, outfile));
+}
+
+int thumbrs_jpeg_save_markers(void *cinfo, int marker_code, unsigned int length_limit)
+{
+    THUMBRS_TRY(jpeg_save_markers(cinfo, marker_code, length_limit));
+}
+
+int thumbrs_jpeg_read_header(void *cinfo, int require_image)
+{
+    THUMBRS_TRY(jpeg_read_header(cinfo, require_image));
+}
+
+int thumbrs_jpeg_calc_output_dimensions(void *cinfo)
+{
+    THUMBRS_TRY(jpeg_calc_output_dimensions(cinfo));
+}
+
+int thumbrs_jpeg_start_decompress(void *cinfo)
+{
+    THUMBRS_TRY(jpeg_start_decompress(cinfo));
+}
+
+int thumbrs_jpeg_read_scanlines(void *cinfo, unsigned char **scanlines, unsigned int max_lines)
+{
+    THUMBRS_TRY(jpeg_read_scanlines(cinfo, scanlines, max_lines));
+}
+
+int thumbrs_jpeg_finish_decompress(void *cinfo)
+{
+    THUMBRS_TRY(jpeg_finish_decompress(cinfo));
+}
+
+int thumbrs_jpeg_set_defaults(void *cinfo)
+{
+    THUMBRS_TRY(jpeg_set_defaults(cinfo));
+}
+
+int thumbrs_jpeg_set_quality(void *cinfo, int quality, int force_baseline)
+{
+    THUMBRS_TRY(jpeg_set_quality(cinfo, quality, force_baseline));
+}
+
+int thumbrs_jpeg_simple_progression(void *cinfo)
+{
+    THUMBRS_TRY(jpeg_simple_progression(cinfo));
+}
+
+int thumbrs_jpeg_c_set_bool_param(void *cinfo, int param, int value)
+{
+    THUMBRS_TRY(jpeg_c_set_bool_param(cinfo, param, value));
+}
+
+int thumbrs_jpeg_c_set_int_param(void *cinfo, int param, int value)
+{
+    THUMBRS_TRY(jpeg_c_set_int_param(cinfo, param, value));
+}
+
+int thumbrs_jpeg_start_compress(void *cinfo, int write_all_tables)
+{
+    THUMBRS_TRY(jpeg_start_compress(cinfo, write_all_tables));
+}
+
+int thumbrs_jpeg_write_marker(void *cinfo, int marker, const unsigned char *dataptr, unsigned int datalen)
+{
+    THUMBRS_TRY(jpeg_write_marker(cinfo, marker, dataptr, datalen));
+}
+
+int thumbrs_jpeg_write_scanlines(void *cinfo, unsigned char **scanlines, unsigned int num_lines)
+{
+    THUMBRS_TRY(jpeg_write_scanlines(cinfo, scanlines, num_lines));
+}
+
+int thumbrs_jpeg_finish_compress(void *cinfo)
+{
+    THUMBRS_TRY(jpeg_finish_compress(cinfo));
+}
@@ -2,30 +2,337 @@ extern crate libc;
 extern crate mozjpeg_sys;
 extern crate image;
 
-use std::path::Path;
+use std::error;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
 use std::ptr;
+use std::slice;
+use std::str::FromStr;
 
-use image::DynamicImage;
+use image::{DynamicImage, ImageBuffer, ImageLuma8, ImageRgb8};
 
 use std::mem;
 
 use mozjpeg_sys::*;
-use std::ffi::CString;
-use libc::{fopen, fclose, malloc, free};
+use std::ffi::{CStr, CString};
+use libc::{c_char, c_int, c_uint, c_ulong, fopen, fclose, FILE};
+
+use color::{self, PixelLayout};
 
 // Basically, this code follws the C example here:
 // https://github.com/mozilla/mozjpeg/blob/master/example.c
 
-pub fn write_jpeg (input_image: &DynamicImage, output_path: &Path, quality: u8)
+/// Errors reported by the libjpeg based reader and writer.
+#[derive(Debug)]
+pub enum JpegError {
+    /// The file could not be opened for reading or writing.
+    Open(PathBuf, io::Error),
+    /// libjpeg gave up while decoding the file.
+    Corrupt(String),
+    /// The decoded image has a color space we cannot convert.
+    UnsupportedColorSpace(J_COLOR_SPACE),
+    /// libjpeg gave up while encoding the image.
+    Write(String),
+}
+
+impl fmt::Display for JpegError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            JpegError::Open(ref path, ref err) => write!(f, "could not open '{}': {}", path.display(), err),
+            JpegError::Corrupt(ref msg) => write!(f, "corrupt JPEG data: {}", msg),
+            JpegError::UnsupportedColorSpace(ref cs) => write!(f, "unsupported JPEG color space {:?}", cs),
+            JpegError::Write(ref msg) => write!(f, "could not write JPEG: {}", msg),
+        }
+    }
+}
+
+impl error::Error for JpegError {
+    fn description(&self) -> &str {
+        match *self {
+            JpegError::Open(..) => "could not open file",
+            JpegError::Corrupt(_) => "corrupt JPEG data",
+            JpegError::UnsupportedColorSpace(_) => "unsupported JPEG color space",
+            JpegError::Write(_) => "could not write JPEG",
+        }
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        match *self {
+            JpegError::Open(_, ref err) => Some(err),
+            _ => None
+        }
+    }
+}
+
+// libjpeg's default `error_exit` calls `exit()`, and unwinding out of it
+// would cross C frames. Instead, every libjpeg call that can fail goes
+// through a wrapper in `jpeg_shim.c`, to which `thumbrs_jpeg_error_exit`
+// longjmps back; the wrapper then returns 0.
+extern "C" {
+    // `thumbrs_jpeg_error_exit`, with the type of `jpeg_error_mgr.error_exit`
+    static thumbrs_jpeg_error_exit_fn: extern "C" fn(&mut jpeg_common_struct);
+
+    fn thumbrs_jpeg_create_decompress(cinfo: &mut jpeg_decompress_struct, version: c_int, structsize: size_t) -> c_int;
+    fn thumbrs_jpeg_create_compress(cinfo: &mut jpeg_compress_struct, version: c_int, structsize: size_t) -> c_int;
+    fn thumbrs_jpeg_stdio_src(cinfo: &mut jpeg_decompress_struct, infile: *mut FILE) -> c_int;
+    fn thumbrs_jpeg_mem_src(cinfo: &mut jpeg_decompress_struct, inbuffer: *const u8, insize: c_ulong) -> c_int;
+    fn thumbrs_jpeg_stdio_dest(cinfo: &mut jpeg_compress_struct, outfile: *mut FILE) -> c_int;
+    fn thumbrs_jpeg_save_markers(cinfo: &mut jpeg_decompress_struct, marker_code: c_int, length_limit: c_uint) -> c_int;
+    fn thumbrs_jpeg_read_header(cinfo: &mut jpeg_decompress_struct, require_image: c_int) -> c_int;
+    fn thumbrs_jpeg_calc_output_dimensions(cinfo: &mut jpeg_decompress_struct) -> c_int;
+    fn thumbrs_jpeg_start_decompress(cinfo: &mut jpeg_decompress_struct) -> c_int;
+    fn thumbrs_jpeg_read_scanlines(cinfo: &mut jpeg_decompress_struct, scanlines: *mut *mut u8, max_lines: c_uint) -> c_int;
+    fn thumbrs_jpeg_finish_decompress(cinfo: &mut jpeg_decompress_struct) -> c_int;
+    fn thumbrs_jpeg_set_defaults(cinfo: &mut jpeg_compress_struct) -> c_int;
+    fn thumbrs_jpeg_set_quality(cinfo: &mut jpeg_compress_struct, quality: c_int, force_baseline: c_int) -> c_int;
+    fn thumbrs_jpeg_simple_progression(cinfo: &mut jpeg_compress_struct) -> c_int;
+    fn thumbrs_jpeg_c_set_bool_param(cinfo: &mut jpeg_compress_struct, param: J_BOOLEAN_PARAM, value: c_int) -> c_int;
+    fn thumbrs_jpeg_c_set_int_param(cinfo: &mut jpeg_compress_struct, param: J_INT_PARAM, value: c_int) -> c_int;
+    fn thumbrs_jpeg_start_compress(cinfo: &mut jpeg_compress_struct, write_all_tables: c_int) -> c_int;
+    fn thumbrs_jpeg_write_marker(cinfo: &mut jpeg_compress_struct, marker: c_int, dataptr: *const u8, datalen: c_uint) -> c_int;
+    fn thumbrs_jpeg_write_scanlines(cinfo: &mut jpeg_compress_struct, scanlines: *const *const u8, num_lines: c_uint) -> c_int;
+    fn thumbrs_jpeg_finish_compress(cinfo: &mut jpeg_compress_struct) -> c_int;
+}
+
+// Calls the `jpeg_shim.c` wrapper `$f` on `$cinfo`, returning libjpeg's
+// message from the enclosing function if it failed.
+macro_rules! jpeg_try {
+    ($f:ident($cinfo:expr $(, $arg:expr)*)) => {
+        if $f($cinfo $(, $arg)*) == 0 {
+            return Err(format_message(&mut (*$cinfo).common));
+        }
+    }
+}
+
+// Warnings (e.g. "premature end of data segment") go to the log instead of
+// stderr.
+extern "C" fn output_message(cinfo: &mut jpeg_common_struct) {
+    warn!("libjpeg: {}", unsafe { format_message(cinfo) });
+}
+
+unsafe fn format_message(cinfo: &mut jpeg_common_struct) -> String {
+    let err = cinfo.err;
+    // JMSG_LENGTH_MAX; mozjpeg-sys declares the buffer as an immutable
+    // 80 byte array, so the function is called with a pointer instead
+    let mut buffer = [0u8; 200];
+    match (*err).format_message {
+        Some(format) => {
+            let format: extern "C" fn(&mut jpeg_common_struct, *mut c_char) = mem::transmute(format);
+            format(cinfo, buffer.as_mut_ptr() as *mut c_char);
+            CStr::from_ptr(buffer.as_ptr() as *const c_char).to_string_lossy().into_owned()
+        },
+        None => format!("libjpeg error code {}", (*err).msg_code)
+    }
+}
+
+unsafe fn new_error_mgr() -> jpeg_error_mgr {
+    let mut err: jpeg_error_mgr = mem::zeroed();
+    jpeg_std_error(&mut err);
+    err.error_exit = Some(thumbrs_jpeg_error_exit_fn);
+    err.output_message = Some(output_message);
+    err
+}
+
+// Paths are bytes on unix, so any path can be passed to `fopen`.
+#[cfg(unix)]
+fn path_to_cstring(path: &Path) -> Result<CString, JpegError> {
+    use std::os::unix::ffi::OsStrExt;
+    CString::new(path.as_os_str().as_bytes()).map_err(|err| JpegError::Open(path.to_path_buf(), err.into()))
+}
+
+#[cfg(not(unix))]
+fn path_to_cstring(path: &Path) -> Result<CString, JpegError> {
+    match path.to_str() {
+        Some(s) => CString::new(s).map_err(|err| JpegError::Open(path.to_path_buf(), err.into())),
+        None => Err(JpegError::Open(path.to_path_buf(),
+            io::Error::new(io::ErrorKind::InvalidInput, "path is not valid unicode")))
+    }
+}
+
+fn open_file(path: &Path, mode: &str) -> Result<*mut FILE, JpegError> {
+    let filename = try!(path_to_cstring(path));
+    let openmode = CString::new(mode).unwrap();
+
+    let file = unsafe { fopen(filename.as_ptr(), openmode.as_ptr()) };
+    if file.is_null() {
+        return Err(JpegError::Open(path.to_path_buf(), io::Error::last_os_error()));
+    }
+    Ok(file)
+}
+
+/// Chroma subsampling of encoded JPEGs.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChromaSubsampling {
+    /// Full resolution color, for small images and sharp colored edges.
+    Yuv444,
+    /// Color at half resolution in both directions.
+    Yuv420,
+}
+
+impl FromStr for ChromaSubsampling {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ChromaSubsampling, String> {
+        match s {
+            "4:4:4" | "444" => Ok(ChromaSubsampling::Yuv444),
+            "4:2:0" | "420" => Ok(ChromaSubsampling::Yuv420),
+            _ => Err(format!("unknown chroma subsampling '{}'", s))
+        }
+    }
+}
+
+/// Tuning of the mozjpeg encoder. The defaults are those of mozjpeg's
+/// `jpeg_set_defaults`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct JpegEncodeOptions {
+    /// Write a progressive JPEG (with optimized scans).
+    pub progressive: bool,
+    /// Use trellis quantization.
+    pub trellis: bool,
+    /// One of mozjpeg's quantization table presets (0-8), e.g. 0 for the
+    /// tables of the JPEG standard; `None` keeps mozjpeg's default.
+    pub quant_table: Option<u8>,
+    pub subsampling: ChromaSubsampling,
+    /// Compute optimal Huffman tables instead of using the standard ones.
+    pub optimize_coding: bool,
+    /// Smooth the input before encoding (0-100), hiding noise and dithering.
+    pub smoothing: u8,
+    /// Embed the sRGB ICC profile, instead of leaving the color space to be
+    /// assumed.
+    pub embed_icc_profile: bool,
+}
+
+impl Default for JpegEncodeOptions {
+    fn default() -> JpegEncodeOptions {
+        JpegEncodeOptions {
+            progressive: true,
+            trellis: true,
+            quant_table: None,
+            subsampling: ChromaSubsampling::Yuv420,
+            optimize_coding: true,
+            smoothing: 0,
+            embed_icc_profile: false,
+        }
+    }
+}
+
+// Applies `options` to `cinfo`, after `jpeg_set_defaults` and before
+// `jpeg_set_quality` (which scales the selected quantization table).
+unsafe fn set_encode_options(cinfo: &mut jpeg_compress_struct, options: &JpegEncodeOptions) -> Result<(), String> {
+    jpeg_try!(thumbrs_jpeg_c_set_bool_param(cinfo, JBOOLEAN_TRELLIS_QUANT, options.trellis as i32));
+    if let Some(table) = options.quant_table {
+        jpeg_try!(thumbrs_jpeg_c_set_int_param(cinfo, JINT_BASE_QUANT_TBL_IDX, table as i32));
+    }
+
+    if options.progressive {
+        jpeg_try!(thumbrs_jpeg_simple_progression(cinfo));
+    } else {
+        jpeg_try!(thumbrs_jpeg_c_set_bool_param(cinfo, JBOOLEAN_OPTIMIZE_SCANS, false as i32));
+        cinfo.num_scans = 0;
+        cinfo.scan_info = ptr::null();
+    }
+
+    let luma_factor = match options.subsampling {
+        ChromaSubsampling::Yuv444 => 1,
+        ChromaSubsampling::Yuv420 => 2,
+    };
+    (*cinfo.comp_info).h_samp_factor = luma_factor;
+    (*cinfo.comp_info).v_samp_factor = luma_factor;
+
+    cinfo.optimize_coding = options.optimize_coding as i32;
+    cinfo.smoothing_factor = options.smoothing as i32;
+    Ok(())
+}
+
+pub fn write_jpeg (input_image: &DynamicImage, output_path: &Path, quality: u8) -> Result<(), JpegError>
+{
+    write_jpeg_with_options(input_image, output_path, quality, &JpegEncodeOptions::default())
+}
+
+/// Like `write_jpeg`, with control over the encoder settings.
+pub fn write_jpeg_with_options (
+    input_image: &DynamicImage,
+    output_path: &Path,
+    quality: u8,
+    options: &JpegEncodeOptions) -> Result<(), JpegError>
+{
+    let outfile = try!(open_file(output_path, "wb"));
+    let result = compress(input_image, quality, options, |cinfo| unsafe { thumbrs_jpeg_stdio_dest(cinfo, outfile) });
+    unsafe { fclose(outfile) };
+    result
+}
+
+// Destination manager writing into a `Vec` owned by Rust, which is freed
+// once whether or not libjpeg finished. libjpeg only knows about `mgr`,
+// which the callbacks cast back to the whole struct.
+#[repr(C)]
+struct VecDestination {
+    mgr: jpeg_destination_mgr,
+    data: Vec<u8>,
+}
+
+const VEC_DESTINATION_INITIAL_SIZE: usize = 64 * 1024;
+
+extern "C" fn init_vec_destination(cinfo: &mut jpeg_compress_struct) {
+    let dest = unsafe { &mut *(cinfo.dest as *mut VecDestination) };
+    dest.data.resize(VEC_DESTINATION_INITIAL_SIZE, 0);
+    dest.mgr.next_output_byte = dest.data.as_mut_ptr();
+    dest.mgr.free_in_buffer = dest.data.len() as size_t;
+}
+
+// Called when the buffer is full.
+extern "C" fn empty_vec_destination(cinfo: &mut jpeg_compress_struct) -> boolean {
+    let dest = unsafe { &mut *(cinfo.dest as *mut VecDestination) };
+    let len = dest.data.len();
+    dest.data.resize(len * 2, 0);
+    dest.mgr.next_output_byte = unsafe { dest.data.as_mut_ptr().offset(len as isize) };
+    dest.mgr.free_in_buffer = len as size_t;
+    1
+}
+
+extern "C" fn term_vec_destination(cinfo: &mut jpeg_compress_struct) {
+    let dest = unsafe { &mut *(cinfo.dest as *mut VecDestination) };
+    let len = dest.data.len() - dest.mgr.free_in_buffer as usize;
+    dest.data.truncate(len);
+}
+
+/// Encodes `input_image` as JPEG into memory.
+pub fn encode_jpeg_to_vec (
+    input_image: &DynamicImage,
+    quality: u8,
+    options: &JpegEncodeOptions) -> Result<Vec<u8>, JpegError>
+{
+    let mut dest = VecDestination {
+        mgr: unsafe { mem::zeroed() },
+        data: Vec::new()
+    };
+    dest.mgr.init_destination = Some(init_vec_destination);
+    dest.mgr.empty_output_buffer = Some(empty_vec_destination);
+    dest.mgr.term_destination = Some(term_vec_destination);
+
+    try!(compress(input_image, quality, options, |cinfo| {
+        cinfo.dest = &mut dest as *mut VecDestination as *mut jpeg_destination_mgr;
+        1
+    }));
+    Ok(dest.data)
+}
+
+// Encodes `input_image` to the destination manager installed by `set_dest`,
+// which returns 0 if libjpeg failed.
+fn compress<F> (
+    input_image: &DynamicImage,
+    quality: u8,
+    options: &JpegEncodeOptions,
+    set_dest: F) -> Result<(), JpegError>
+    where F: FnOnce(&mut jpeg_compress_struct) -> c_int
 {
     let input_rgb8 = input_image.to_rgb();
     let (width, height) = input_rgb8.dimensions();
     let input_data = input_rgb8.as_ptr();
 
     unsafe {
-
-        let mut err = mem::zeroed();
-        jpeg_std_error(&mut err);
+        let mut err = new_error_mgr();
 
         let mut cinfo: jpeg_compress_struct = mem::zeroed();
         let size = mem::size_of_val(&cinfo) as size_t;
@@ -34,114 +341,393 @@ pub fn write_jpeg (input_image: &DynamicImage, output_path: &Path, quality: u8)
             panic!("Not linked to mozjpeg?");
         }
 
-        jpeg_CreateCompress(&mut cinfo, JPEG_LIB_VERSION, size);
+        let result = (|| {
+            jpeg_try!(thumbrs_jpeg_create_compress(&mut cinfo, JPEG_LIB_VERSION, size));
+            if set_dest(&mut cinfo) == 0 {
+                return Err(format_message(&mut cinfo.common));
+            }
+
+            cinfo.image_width = width;
+            cinfo.image_height = height;
+            cinfo.input_components = 3;
+            cinfo.in_color_space = JCS_RGB;
+
+            jpeg_try!(thumbrs_jpeg_set_defaults(&mut cinfo));
+            try!(set_encode_options(&mut cinfo, options));
+            jpeg_try!(thumbrs_jpeg_set_quality(&mut cinfo, quality as i32, true as i32));
+            jpeg_try!(thumbrs_jpeg_start_compress(&mut cinfo, true as i32));
+            if options.embed_icc_profile {
+                try!(write_icc_profile(&mut cinfo, &color::srgb_profile()));
+            }
+            let row_stride:isize = width as isize * 3;
+            while cinfo.next_scanline < cinfo.image_height {
+                let row_pointer = &input_data.offset(cinfo.next_scanline as isize * row_stride);
+                jpeg_try!(thumbrs_jpeg_write_scanlines(&mut cinfo, row_pointer, 1));
+            }
+            jpeg_try!(thumbrs_jpeg_finish_compress(&mut cinfo));
+            Ok(())
+        })();
 
-        let filename = CString::new(output_path.to_str().unwrap()).unwrap();
-        let openmode = CString::new("wb").unwrap();
-        let outfile = fopen (filename.as_ptr(), openmode.as_ptr());
-        if outfile.is_null() {
-            panic!("Could not write to file");
+        jpeg_destroy_compress(&mut cinfo);
+
+        result.map_err(JpegError::Write)
+    }
+}
+
+// ICC profiles are stored in APP2 markers, starting with this signature, a
+// sequence number (from 1) and the number of markers; large profiles are
+// split across several markers.
+const ICC_MARKER: i32 = 0xE2;
+const ICC_SIGNATURE: &'static [u8] = b"ICC_PROFILE\0";
+// 65535 bytes per marker, minus the length field, signature and numbering
+const ICC_CHUNK_SIZE: usize = 65535 - 2 - 12 - 2;
+
+// Writes `icc` after `jpeg_start_compress`, before the image data.
+unsafe fn write_icc_profile(cinfo: &mut jpeg_compress_struct, icc: &[u8]) -> Result<(), String> {
+    let count = (icc.len() + ICC_CHUNK_SIZE - 1) / ICC_CHUNK_SIZE;
+    for (i, chunk) in icc.chunks(ICC_CHUNK_SIZE).enumerate() {
+        let mut data = ICC_SIGNATURE.to_vec();
+        data.push(i as u8 + 1);
+        data.push(count as u8);
+        data.extend_from_slice(chunk);
+        jpeg_try!(thumbrs_jpeg_write_marker(cinfo, ICC_MARKER, data.as_ptr(), data.len() as c_uint));
+    }
+    Ok(())
+}
+
+// Reassembles the ICC profile from the markers saved by `jpeg_save_markers`.
+unsafe fn saved_icc_profile(cinfo: &jpeg_decompress_struct) -> Option<Vec<u8>> {
+    let mut chunks = Vec::new();
+    let mut marker = cinfo.marker_list;
+    while !marker.is_null() {
+        let m = &*marker;
+        let data = slice::from_raw_parts(m.data, m.data_length as usize);
+        if m.marker as i32 == ICC_MARKER && data.len() > ICC_SIGNATURE.len() + 2 && data.starts_with(ICC_SIGNATURE) {
+            chunks.push((data[ICC_SIGNATURE.len()], &data[ICC_SIGNATURE.len() + 2..]));
         }
+        marker = m.next;
+    }
 
-        jpeg_stdio_dest(&mut cinfo, outfile);
+    if chunks.is_empty() {
+        return None;
+    }
+    chunks.sort_by_key(|&(seq, _)| seq);
+    Some(chunks.iter().flat_map(|&(_, data)| data.iter().cloned()).collect())
+}
 
-        cinfo.image_width = width;
-        cinfo.image_height = height;
-        cinfo.input_components = 3;
-        cinfo.in_color_space = JCS_RGB;
+// APP14, written by Adobe software; libjpeg still interprets it when it is
+// saved
+const ADOBE_MARKER: i32 = 0xEE;
+const ADOBE_SIGNATURE: &'static [u8] = b"Adobe";
+
+// Whether an Adobe marker was saved by `jpeg_save_markers`.
+unsafe fn saw_adobe_marker(cinfo: &jpeg_decompress_struct) -> bool {
+    let mut marker = cinfo.marker_list;
+    while !marker.is_null() {
+        let m = &*marker;
+        let data = slice::from_raw_parts(m.data, m.data_length as usize);
+        if m.marker as i32 == ADOBE_MARKER && data.starts_with(ADOBE_SIGNATURE) {
+            return true;
+        }
+        marker = m.next;
+    }
+    false
+}
 
-        jpeg_set_defaults(&mut cinfo);
-        jpeg_set_quality(&mut cinfo, quality as i32, true as i32);
-        jpeg_start_compress(&mut cinfo, true as i32);
-        let row_stride:isize = width as isize * 3;
-        while cinfo.next_scanline < cinfo.image_height {
-            let row_pointer = &input_data.offset(cinfo.next_scanline as isize * row_stride);
-            jpeg_write_scanlines(&mut cinfo, row_pointer, 1);
+// Converts the decoded `pixels` to sRGB RGB pixels if `icc` describes
+// another color space. `None` if no conversion is needed or possible.
+fn icc_to_srgb(pixels: &[u8], layout: Option<PixelLayout>, icc: Option<&[u8]>) -> Option<Vec<u8>> {
+    let (layout, icc) = match (layout, icc) {
+        (Some(layout), Some(icc)) if !color::is_srgb(icc) => (layout, icc),
+        _ => return None
+    };
+    match color::to_srgb(pixels, layout, icc) {
+        Ok(rgb) => Some(rgb),
+        Err(msg) => {
+            warn!("ignoring embedded ICC profile: {}", msg);
+            None
         }
-        jpeg_finish_compress(&mut cinfo);
-        fclose(outfile);
-        jpeg_destroy_compress(&mut cinfo);
     }
 }
 
-pub fn read_jpeg(input_path: &Path) -> DynamicImage
-{
-    let input_path = input_path.to_str()
-        .unwrap();
+// Naive CMYK to RGB conversion, without any color management.
+fn cmyk_to_rgb(cmyk: &[u8], inverted: bool) -> Vec<u8> {
+    let mut rgb = Vec::with_capacity(cmyk.len() / 4 * 3);
+    for px in cmyk.chunks(4) {
+        // `c`, `m`, `y` and `k` hold 255 minus the respective ink coverage
+        let (c, m, y, k) = if inverted {
+            (px[0] as u32, px[1] as u32, px[2] as u32, px[3] as u32)
+        } else {
+            (255 - px[0] as u32, 255 - px[1] as u32, 255 - px[2] as u32, 255 - px[3] as u32)
+        };
+        rgb.push((c * k / 255) as u8);
+        rgb.push((m * k / 255) as u8);
+        rgb.push((y * k / 255) as u8);
+    }
+    rgb
+}
 
-    unsafe {
-        // open file
-        let filename = CString::new(input_path).unwrap();
-        let openmode = CString::new("rb").unwrap();
-        let infile = fopen (filename.as_ptr(), openmode.as_ptr());
-        if infile.is_null() {
-            panic!("Could not read file");
+// Largest denominator `d` out of 8, 4, 2 so that the image scaled by `1/d`
+// (as done by libjpeg during decoding) is still at least `min_w` x `min_h`.
+fn scale_denom(width: u32, height: u32, min_w: u32, min_h: u32) -> u32 {
+    for &denom in [8, 4, 2].iter() {
+        if (width + denom - 1) / denom >= min_w && (height + denom - 1) / denom >= min_h {
+            return denom;
         }
+    }
+    1
+}
 
-        let mut err = mem::zeroed();
-        jpeg_std_error(&mut err);
+pub fn read_jpeg(input_path: &Path) -> Result<DynamicImage, JpegError>
+{
+    read_jpeg_impl(input_path, None)
+}
+
+/// Reads a JPEG, letting libjpeg downscale it by 1/2, 1/4 or 1/8 while
+/// decoding. The smallest scale still covering `min_w` x `min_h` is used,
+/// which is a lot faster than decoding at full resolution.
+pub fn read_jpeg_scaled(input_path: &Path, min_w: u32, min_h: u32) -> Result<DynamicImage, JpegError>
+{
+    read_jpeg_impl(input_path, Some((min_w, min_h)))
+}
+
+/// Reads the ICC profile embedded in a JPEG, without decoding the image.
+pub fn read_jpeg_icc_profile(input_path: &Path) -> Result<Option<Vec<u8>>, JpegError> {
+    read_jpeg_header(input_path).map(|(_, icc)| icc)
+}
+
+/// Reads the dimensions of a JPEG and its embedded ICC profile in one pass
+/// over the header, without decoding the image.
+pub fn read_jpeg_header(input_path: &Path) -> Result<((u32, u32), Option<Vec<u8>>), JpegError> {
+    let infile = try!(open_file(input_path, "rb"));
+
+    unsafe {
+        let mut err = new_error_mgr();
 
         let mut cinfo: jpeg_decompress_struct = mem::zeroed();
         let size = mem::size_of_val(&cinfo) as size_t;
         cinfo.common.err = &mut err;
 
-        jpeg_CreateDecompress(&mut cinfo, JPEG_LIB_VERSION, size);
+        let result = (|| {
+            jpeg_try!(thumbrs_jpeg_create_decompress(&mut cinfo, JPEG_LIB_VERSION, size));
+            jpeg_try!(thumbrs_jpeg_stdio_src(&mut cinfo, infile));
+            jpeg_try!(thumbrs_jpeg_save_markers(&mut cinfo, ICC_MARKER, 0xFFFF));
+            jpeg_try!(thumbrs_jpeg_read_header(&mut cinfo, true as i32));
+            jpeg_try!(thumbrs_jpeg_calc_output_dimensions(&mut cinfo));
+            Ok(((cinfo.output_width, cinfo.output_height), saved_icc_profile(&cinfo)))
+        })();
 
-        jpeg_stdio_src(&mut cinfo, infile);
-        jpeg_read_header(&mut cinfo, true as i32);
-        jpeg_start_decompress(&mut cinfo);
+        jpeg_destroy_decompress(&mut cinfo);
+        fclose(infile);
 
-        let mut output_image = DynamicImage::new_rgb8 (cinfo.output_width, cinfo.output_height);
-        let output_buffer = output_image.as_mut_rgb8().unwrap().as_mut_ptr();
+        result.map_err(JpegError::Corrupt)
+    }
+}
 
-        let row_stride:u64 = cinfo.output_width as u64 * cinfo.output_components as u64;
-        let mut buffer = malloc(row_stride as usize) as *mut u8;
+/// Decodes a JPEG held in memory, e.g. as received over the network.
+pub fn read_jpeg_from_slice(data: &[u8]) -> Result<DynamicImage, JpegError>
+{
+    read_jpeg_from_slice_impl(data, None, None)
+}
 
-        while cinfo.output_scanline < cinfo.output_height {
-            jpeg_read_scanlines(&mut cinfo, &mut buffer, 1);
+/// Like `read_jpeg_scaled`, for a JPEG held in memory.
+pub fn read_jpeg_from_slice_scaled(data: &[u8], min_w: u32, min_h: u32) -> Result<DynamicImage, JpegError>
+{
+    read_jpeg_from_slice_impl(data, Some((min_w, min_h)), None)
+}
 
-            let output_row = &output_buffer.offset(cinfo.output_scanline as isize * row_stride as isize);
-            ptr::copy(buffer, *output_row, row_stride as usize);
-        }
+/// Like `read_jpeg_from_slice`, but converts the pixels from the color space
+/// described by `icc` if the JPEG embeds no ICC profile itself, e.g. for
+/// previews embedded in a file tagged with a profile.
+pub fn read_jpeg_from_slice_with_profile(data: &[u8], icc: Option<&[u8]>) -> Result<DynamicImage, JpegError>
+{
+    read_jpeg_from_slice_impl(data, None, icc)
+}
+
+fn read_jpeg_from_slice_impl(data: &[u8], min_size: Option<(u32, u32)>, icc: Option<&[u8]>) -> Result<DynamicImage, JpegError>
+{
+    decompress(min_size, icc, |cinfo| unsafe { thumbrs_jpeg_mem_src(cinfo, data.as_ptr(), data.len() as c_ulong) })
+}
+
+fn read_jpeg_impl(input_path: &Path, min_size: Option<(u32, u32)>) -> Result<DynamicImage, JpegError>
+{
+    let infile = try!(open_file(input_path, "rb"));
+    let result = decompress(min_size, None, |cinfo| unsafe { thumbrs_jpeg_stdio_src(cinfo, infile) });
+    unsafe { fclose(infile) };
+    result
+}
+
+// Decodes the JPEG from the source manager installed by `set_src`, which
+// returns 0 if libjpeg failed. `fallback_icc` is used if the JPEG embeds no
+// ICC profile.
+fn decompress<F>(min_size: Option<(u32, u32)>, fallback_icc: Option<&[u8]>, set_src: F) -> Result<DynamicImage, JpegError>
+    where F: FnOnce(&mut jpeg_decompress_struct) -> c_int
+{
+    unsafe {
+        let mut err = new_error_mgr();
+
+        let mut cinfo: jpeg_decompress_struct = mem::zeroed();
+        let size = mem::size_of_val(&cinfo) as size_t;
+        cinfo.common.err = &mut err;
 
-        free(buffer as *mut c_void);
+        let result = (|| {
+            jpeg_try!(thumbrs_jpeg_create_decompress(&mut cinfo, JPEG_LIB_VERSION, size));
+            if set_src(&mut cinfo) == 0 {
+                return Err(format_message(&mut cinfo.common));
+            }
+            jpeg_try!(thumbrs_jpeg_save_markers(&mut cinfo, ICC_MARKER, 0xFFFF));
+            jpeg_try!(thumbrs_jpeg_save_markers(&mut cinfo, ADOBE_MARKER, 0xFFFF));
+            jpeg_try!(thumbrs_jpeg_read_header(&mut cinfo, true as i32));
+            if let Some((min_w, min_h)) = min_size {
+                cinfo.scale_num = 1;
+                cinfo.scale_denom = scale_denom(cinfo.image_width, cinfo.image_height, min_w, min_h);
+            }
+            // let libjpeg do the YCbCr->RGB and YCCK->CMYK conversions,
+            // CMYK->RGB is done by us below
+            cinfo.out_color_space = match cinfo.jpeg_color_space {
+                JCS_GRAYSCALE => JCS_GRAYSCALE,
+                JCS_CMYK | JCS_YCCK => JCS_CMYK,
+                _ => JCS_RGB
+            };
+            jpeg_try!(thumbrs_jpeg_start_decompress(&mut cinfo));
+
+            let (width, height) = (cinfo.output_width, cinfo.output_height);
+            let row_stride = width as usize * cinfo.output_components as usize;
+            let mut pixels = vec![0u8; row_stride * height as usize];
+
+            while cinfo.output_scanline < cinfo.output_height {
+                let offset = cinfo.output_scanline as usize * row_stride;
+                let mut row = pixels[offset..].as_mut_ptr();
+                jpeg_try!(thumbrs_jpeg_read_scanlines(&mut cinfo, &mut row, 1));
+            }
+
+            // Photoshop (and most software following it) writes inverted
+            // CMYK data, flagged by the Adobe APP14 marker.
+            let inverted = saw_adobe_marker(&cinfo);
+            let layout = match (cinfo.out_color_space, cinfo.output_components) {
+                (JCS_GRAYSCALE, 1) => Some(PixelLayout::Gray),
+                (JCS_RGB, 3) => Some(PixelLayout::Rgb),
+                (JCS_CMYK, 4) if inverted => Some(PixelLayout::InvertedCmyk),
+                (JCS_CMYK, 4) => Some(PixelLayout::Cmyk),
+                _ => None
+            };
+            let embedded_icc = saved_icc_profile(&cinfo);
+            let icc = embedded_icc.as_ref().map(|icc| &icc[..]).or(fallback_icc);
+            let converted = icc_to_srgb(&pixels, layout, icc);
+
+            let output_image = match converted {
+                Some(rgb) => ImageBuffer::from_raw(width, height, rgb).map(ImageRgb8),
+                None => match layout {
+                    Some(PixelLayout::Gray) => ImageBuffer::from_raw(width, height, pixels).map(ImageLuma8),
+                    Some(PixelLayout::Rgb) => ImageBuffer::from_raw(width, height, pixels).map(ImageRgb8),
+                    Some(PixelLayout::Cmyk) | Some(PixelLayout::InvertedCmyk) =>
+                        ImageBuffer::from_raw(width, height, cmyk_to_rgb(&pixels, inverted)).map(ImageRgb8),
+                    None => None
+                }
+            };
+
+            jpeg_try!(thumbrs_jpeg_finish_decompress(&mut cinfo));
+
+            Ok(output_image.ok_or(JpegError::UnsupportedColorSpace(cinfo.out_color_space)))
+        })();
 
-        jpeg_finish_decompress(&mut cinfo);
         jpeg_destroy_decompress(&mut cinfo);
-        fclose(infile);
 
-        output_image
+        match result {
+            Ok(r) => r,
+            Err(msg) => Err(JpegError::Corrupt(msg))
+        }
     }
 }
 
-pub fn read_jpeg_size(input_path: &Path) -> (u32, u32) {
-    let input_path = input_path.to_str()
-        .unwrap();
+pub fn read_jpeg_size(input_path: &Path) -> Result<(u32, u32), JpegError> {
+    let infile = try!(open_file(input_path, "rb"));
 
     unsafe {
-        // open file
-        let filename = CString::new(input_path).unwrap();
-        let openmode = CString::new("rb").unwrap();
-        let infile = fopen (filename.as_ptr(), openmode.as_ptr());
-        if infile.is_null() {
-            panic!("Could not read file");
-        }
-
-        let mut err = mem::zeroed();
-        jpeg_std_error(&mut err);
+        let mut err = new_error_mgr();
 
         let mut cinfo: jpeg_decompress_struct = mem::zeroed();
         let size = mem::size_of_val(&cinfo) as size_t;
         cinfo.common.err = &mut err;
 
-        jpeg_CreateDecompress(&mut cinfo, JPEG_LIB_VERSION, size);
-        jpeg_stdio_src(&mut cinfo, infile);
-        jpeg_read_header(&mut cinfo, true as i32);
-        jpeg_calc_output_dimensions(&mut cinfo);
-        jpeg_destroy_decompress(&mut cinfo);
+        let result = (|| {
+            jpeg_try!(thumbrs_jpeg_create_decompress(&mut cinfo, JPEG_LIB_VERSION, size));
+            jpeg_try!(thumbrs_jpeg_stdio_src(&mut cinfo, infile));
+            jpeg_try!(thumbrs_jpeg_read_header(&mut cinfo, true as i32));
+            jpeg_try!(thumbrs_jpeg_calc_output_dimensions(&mut cinfo));
+            Ok((cinfo.output_width, cinfo.output_height))
+        })();
 
+        jpeg_destroy_decompress(&mut cinfo);
         fclose(infile);
 
-        (cinfo.output_width, cinfo.output_height)
+        result.map_err(JpegError::Corrupt)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{GenericImage, ImageBuffer, ImageRgb8, Rgb};
+
+    use super::*;
+
+    fn encoded_test_image() -> Vec<u8> {
+        let img = ImageRgb8(ImageBuffer::from_fn(64, 48, |x, y| Rgb([x as u8 * 4, y as u8 * 5, 128])));
+        encode_jpeg_to_vec(&img, 90, &JpegEncodeOptions::default()).unwrap()
+    }
+
+    #[test]
+    fn decodes_encoded_image() {
+        let img = read_jpeg_from_slice(&encoded_test_image()).unwrap();
+        assert_eq!(img.dimensions(), (64, 48));
+    }
+
+    #[test]
+    fn scale_denom_picks_largest_that_covers_min_size() {
+        assert_eq!(scale_denom(4000, 3000, 150, 113), 8);
+        assert_eq!(scale_denom(4000, 3000, 1000, 750), 4);
+        assert_eq!(scale_denom(4000, 3000, 1001, 750), 2);
+        assert_eq!(scale_denom(4000, 3000, 2000, 1501), 1);
+        // portrait images are limited by their width
+        assert_eq!(scale_denom(3000, 4000, 1000, 750), 2);
+    }
+
+    #[test]
+    fn scale_denom_rounds_scaled_size_up() {
+        // libjpeg makes 4001 / 8 pixels into 501
+        assert_eq!(scale_denom(4001, 3001, 501, 376), 8);
+        assert_eq!(scale_denom(4000, 3000, 501, 375), 4);
+    }
+
+    #[test]
+    fn scale_denom_of_small_images() {
+        assert_eq!(scale_denom(100, 100, 150, 150), 1);
+        assert_eq!(scale_denom(1, 1, 1, 1), 8);
+        assert_eq!(scale_denom(0, 0, 1, 1), 1);
+    }
+
+    #[test]
+    fn decodes_at_reduced_scale() {
+        let img = read_jpeg_from_slice_scaled(&encoded_test_image(), 16, 12).unwrap();
+        assert_eq!(img.dimensions(), (16, 12));
+    }
+
+    #[test]
+    fn truncated_jpeg_is_corrupt() {
+        let data = encoded_test_image();
+        // cut off before the first scan (SOS marker)
+        let sos = data.windows(2).position(|w| w == [0xFF, 0xDA]).unwrap();
+        match read_jpeg_from_slice(&data[..sos]) {
+            Err(JpegError::Corrupt(_)) => (),
+            other => panic!("expected JpegError::Corrupt, got {:?}", other.map(|img| img.dimensions())),
+        }
+    }
+
+    #[test]
+    fn empty_input_is_corrupt() {
+        match read_jpeg_from_slice(&[]) {
+            Err(JpegError::Corrupt(_)) => (),
+            other => panic!("expected JpegError::Corrupt, got {:?}", other.map(|img| img.dimensions())),
+        }
     }
 }
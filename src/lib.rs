@@ -6,20 +6,48 @@ extern crate log;
 
 extern crate chrono;
 extern crate filetime;
+extern crate glob;
 extern crate image;
+#[cfg(feature = "avif")]
+extern crate imgref;
+extern crate lcms2;
 extern crate libc;
+extern crate libwebp_sys;
 extern crate mozjpeg_sys;
 extern crate num;
+#[cfg(feature = "avif")]
+extern crate ravif;
 extern crate rexiv2;
+#[cfg(feature = "avif")]
+extern crate rgb;
 extern crate serde;
 extern crate serde_json;
 extern crate sha1;
+extern crate toml;
 extern crate walkdir;
 
+pub mod color;
+pub mod config;
+pub mod decode;
+pub mod encode;
+pub mod error;
+pub mod filter;
+pub mod format;
 pub mod jpegimpex;
 pub mod metadata;
+pub mod pool;
+pub mod resample;
+pub mod sharpen;
 pub mod thumbnail;
 
-pub use jpegimpex::{read_jpeg, write_jpeg};
-pub use metadata::{Metadata, FileInfo};
-pub use thumbnail::{make_thumbnail, read_and_rotate};
+pub use config::{Config, DirConfig, ThumbnailSpec, DIR_CONFIG_FILE};
+pub use decode::{decode_image, image_format, read_image, read_image_scaled, read_image_from_slice, read_image_size, read_largest_preview, read_preview, ImageHeader};
+pub use encode::{encode_image, write_image, QualityTarget};
+pub use error::Error;
+pub use filter::{PathFilter, IGNORE_FILE};
+pub use format::{FileFormat, detect_format, sniff_format};
+pub use jpegimpex::{read_jpeg, read_jpeg_scaled, read_jpeg_from_slice, read_jpeg_from_slice_scaled, read_jpeg_from_slice_with_profile, read_jpeg_header, read_jpeg_icc_profile, write_jpeg, encode_jpeg_to_vec, write_jpeg_with_options, ChromaSubsampling, JpegEncodeOptions, JpegError};
+pub use metadata::{Metadata, FileInfo, FileError, ProcessingStage, ThumbnailInfo};
+pub use resample::ResizeFilter;
+pub use sharpen::UnsharpMask;
+pub use thumbnail::{FitMode, OutputFormat, UpscalePolicy, encode_thumbnail, make_thumbnail, make_thumbnails_cascaded, read_and_rotate, read_and_rotate_scaled, read_source, read_source_preview, thumbnail_dimensions, SourceImage};
@@ -4,8 +4,10 @@ extern crate chrono;
 
 use rexiv2::Orientation;
 use std::collections::HashSet;
-use std::path::Path;
-use jpegimpex::read_jpeg_size;
+use std::path::{Path, PathBuf};
+use error::Error;
+use color::profile_description;
+use decode::{image_format, ImageHeader};
 use chrono::datetime::DateTime;
 use chrono::Local;
 use serde::{self, Serialize, Serializer};
@@ -46,6 +48,75 @@ impl serde::Deserialize for OrientationWrapper {
     }
 }
 
+impl serde::Deserialize for ThumbnailInfo {
+    fn deserialize<D>(deserializer: &mut D) -> Result<ThumbnailInfo, D::Error>
+        where D: serde::Deserializer
+    {
+        struct ThumbnailInfoVisitor;
+
+        impl serde::de::Visitor for ThumbnailInfoVisitor {
+            type Value = ThumbnailInfo;
+
+            fn visit_map<V>(&mut self, mut visitor: V) -> Result<ThumbnailInfo, V::Error>
+                where V: serde::de::MapVisitor
+            {
+                let (mut width, mut height, mut format, mut quality) = (None, None, None, None);
+                while let Some(key) = try!(visitor.visit_key::<String>()) {
+                    match &key[..] {
+                        "width" => width = Some(try!(visitor.visit_value())),
+                        "height" => height = Some(try!(visitor.visit_value())),
+                        "format" => format = Some(try!(visitor.visit_value())),
+                        "quality" => quality = try!(visitor.visit_value()),
+                        _ => { try!(visitor.visit_value::<serde::de::impls::IgnoredAny>()); }
+                    }
+                }
+                try!(visitor.end());
+
+                Ok(ThumbnailInfo {
+                    width: match width {
+                        Some(width) => width,
+                        None => try!(visitor.missing_field("width"))
+                    },
+                    height: match height {
+                        Some(height) => height,
+                        None => try!(visitor.missing_field("height"))
+                    },
+                    format: match format {
+                        Some(format) => format,
+                        None => try!(visitor.missing_field("format"))
+                    },
+                    quality: quality
+                })
+            }
+
+            // `[width, height]` of a JPEG thumbnail, as written by older
+            // versions
+            fn visit_seq<V>(&mut self, mut visitor: V) -> Result<ThumbnailInfo, V::Error>
+                where V: serde::de::SeqVisitor
+            {
+                let width = match try!(visitor.visit()) {
+                    Some(width) => width,
+                    None => return Err(serde::de::Error::invalid_length(0))
+                };
+                let height = match try!(visitor.visit()) {
+                    Some(height) => height,
+                    None => return Err(serde::de::Error::invalid_length(1))
+                };
+                try!(visitor.end());
+
+                Ok(ThumbnailInfo {
+                    width: width,
+                    height: height,
+                    format: OutputFormat::Jpeg,
+                    quality: None
+                })
+            }
+        }
+
+        deserializer.deserialize(ThumbnailInfoVisitor)
+    }
+}
+
 fn get_exif_string(exiv: &rexiv2::Metadata, tags: &HashSet<String>, tag: &str) -> Option<String> {
     if tags.contains(tag) {
         match exiv.get_tag_string(tag) {
@@ -142,111 +213,129 @@ impl Serialize for OrientationWrapper {
 }
 
 impl Metadata {
-    pub fn from(file_path: &Path) -> Option<Metadata> {
+    /// Metadata of an image without EXIF/XMP tags.
+    pub fn without_tags(size: (u32, u32)) -> Metadata {
+        Metadata {
+            size: size,
+            orientation: OrientationWrapper(Orientation::Unspecified),
+            exposure_time: None,
+            iso_speed: None,
+            fnumber: None,
+            lens_model: None,
+            camera_model: None,
+            rating: None,
+            tags: Vec::new(),
+            digikam_pick_label: None,
+            digikam_color_label: None,
+            color_space: None
+        }
+    }
 
-        let size = read_jpeg_size(&file_path);
+    pub fn from(file_path: &Path) -> Result<Metadata, Error> {
+        let format = try!(image_format(&file_path));
+        let header = try!(ImageHeader::read(&file_path, format));
+        Ok(Metadata::from_header(&header))
+    }
+
+    /// Metadata of the image whose `header` was already read.
+    pub fn from_header(header: &ImageHeader) -> Metadata {
+        let color_space = header.icc_profile.as_ref().and_then(|icc| profile_description(icc));
 
-        let file_str = match file_path.to_str() {
-            Some(s) => s,
+        // unreadable tags are not worth failing the file for, and were
+        // already reported when reading the header
+        let exif = match header.exif {
+            Some(ref exif) => exif,
             None => {
-                warn!("path '{}' contains invalid unicode", file_path.display());
-                return None;
+                let mut metadata = Metadata::without_tags(header.size);
+                metadata.color_space = color_space;
+                return metadata;
             }
         };
 
-        let exif = match rexiv2::Metadata::new_from_path(file_str) {
-            Ok(e) => Some(e),
+        let exif_tags = match exif.get_exif_tags() {
+            Ok(tags) => tags.into_iter().collect::<HashSet<_>>(),
             Err(err) => {
-                warn!("image '{}' has no EXIF tags! Error: {}", file_path.display(), err);
-                None
+                warn!("unicode error in EXIF tags: {}", err);
+                HashSet::<String>::new()
             }
         };
 
-        let exif_tags = match exif.as_ref() {
-            Some(exif) => match exif.get_exif_tags() {
-                Ok(tags) => tags.into_iter().collect::<HashSet<_>>(),
-                Err(err) => {
-                    warn!("unicode error in EXIF tags: {}", err);
-                    HashSet::<String>::new()
-                }
-            },
-            None => HashSet::<String>::new()
+        let xmp_tags = match exif.get_xmp_tags() {
+            Ok(tags) => tags.into_iter().collect::<HashSet<_>>(),
+            Err(err) => {
+                warn!("unicode error in XMP tags: {}", err);
+                HashSet::<String>::new()
+            }
         };
 
-        let xmp_tags = match exif.as_ref() {
-            Some(exif) => match exif.get_xmp_tags() {
-                Ok(tags) => tags.into_iter().collect::<HashSet<_>>(),
-                Err(err) => {
-                    warn!("unicode error in XMP tags: {}", err);
-                    HashSet::<String>::new()
+        Metadata {
+            size: header.size,
+            orientation: OrientationWrapper(exif.get_orientation()),
+            exposure_time: exif.get_exposure_time(),
+            iso_speed: exif.get_iso_speed(),
+            fnumber: get_exif_rational(&exif, &exif_tags, "Exif.Photo.FNumber"),
+            lens_model: get_exif_string(&exif, &exif_tags, "Exif.Photo.LensModel"),
+            camera_model: get_exif_string(&exif, &exif_tags, "Exif.Image.Model"),
+            rating: {
+                let rating_str = get_exif_string(&exif, &xmp_tags, "Xmp.xmp.Rating");
+                match rating_str {
+                    Some(s) => match s.parse::<i32>() {
+                        Ok(i) => Some(i),
+                        Err(err) => {
+                            warn!("expected an integer rating, got '{}': {}", s, err);
+                            None
+                        }
+                    },
+                    None => None
                 }
             },
-            None => HashSet::<String>::new()
+            tags: get_exif_multiple_strings(&exif, &xmp_tags, "Xmp.digiKam.TagsList"),
+            digikam_pick_label: get_digikam_pick_label(&exif, &xmp_tags),
+            digikam_color_label: get_digikam_color_label(&exif, &xmp_tags),
+            color_space: color_space
+        }
+    }
+}
+
+impl FileInfo {
+    /// Sets `filename` (and `filename_bytes`, if necessary) to `relpath`.
+    pub fn set_filename(&mut self, relpath: &Path) {
+        self.filename = relpath.to_string_lossy().into_owned();
+        self.filename_bytes = match relpath.to_str() {
+            Some(_) => None,
+            None => path_to_bytes(relpath)
         };
+    }
 
-        Some(Metadata {
-            size: size,
-            orientation: OrientationWrapper(match exif.as_ref() {
-                Some(ref e) => e.get_orientation(),
-                None => Orientation::Unspecified
-            }),
-            exposure_time: match exif.as_ref() {
-                Some(ref e) => match e.get_exposure_time() {
-                    Some(exposure) => Some(exposure),
-                    None => None,
-                },
-                None => None,
-            },
-            iso_speed: match exif.as_ref() {
-                Some(ref e) => e.get_iso_speed(),
-                None => None
-            },
-            fnumber: match exif.as_ref() {
-                Some(ref e) => {
-                    let ratio = get_exif_rational(e, &exif_tags, "Exif.Photo.FNumber");
-                    match ratio {
-                        Some(fnumber) => Some(fnumber),
-                        None => None,
-                    }
-                }
-                None => None
-            },
-            lens_model: match exif.as_ref() {
-                Some(ref e) => get_exif_string(e, &exif_tags, "Exif.Photo.LensModel"),
-                None => None
-            },
-            camera_model: match exif.as_ref() {
-                Some(ref e) => get_exif_string(e, &exif_tags, "Exif.Image.Model"),
-                None => None
-            },
-            rating: match exif.as_ref() {
-                Some(ref e) => {
-                    let rating_str = get_exif_string(&e, &xmp_tags, "Xmp.xmp.Rating");
-                    match rating_str {
-                        Some(s) => match s.parse::<i32>() {
-                            Ok(i) => Some(i),
-                            Err(err) => {
-                                warn!("expected an integer rating, got '{}': {}", s, err);
-                                None
-                            }
-                        },
-                        None => None
-                    }
-                },
-                None => None
-            },
-            tags: match exif.as_ref () {
-                Some(ref e) =>  get_exif_multiple_strings(e, &xmp_tags, "Xmp.digiKam.TagsList"),
-                None => Vec::new()
-            },
-            digikam_pick_label: match exif.as_ref() {
-                Some(ref e) => get_digikam_pick_label(e, &xmp_tags),
-                None => None
-            },
-            digikam_color_label: match exif.as_ref() {
-                Some(ref e) => get_digikam_color_label(e, &xmp_tags),
-                None => None
-            }
-        })
+    /// The path set with `set_filename`, exactly.
+    pub fn path(&self) -> PathBuf {
+        match self.filename_bytes {
+            Some(ref bytes) => path_from_bytes(bytes),
+            None => PathBuf::from(&self.filename)
+        }
     }
 }
+
+#[cfg(unix)]
+fn path_to_bytes(path: &Path) -> Option<Vec<u8>> {
+    use std::os::unix::ffi::OsStrExt;
+    Some(path.as_os_str().as_bytes().to_vec())
+}
+
+#[cfg(unix)]
+fn path_from_bytes(bytes: &[u8]) -> PathBuf {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+    PathBuf::from(OsStr::from_bytes(bytes))
+}
+
+// other platforms hand out unicode paths (almost) exclusively
+#[cfg(not(unix))]
+fn path_to_bytes(_: &Path) -> Option<Vec<u8>> {
+    None
+}
+
+#[cfg(not(unix))]
+fn path_from_bytes(bytes: &[u8]) -> PathBuf {
+    PathBuf::from(String::from_utf8_lossy(bytes).into_owned())
+}
@@ -0,0 +1,124 @@
+//! A fixed-size thread pool and a counting semaphore, used to pipeline
+//! thumbnail generation across many images.
+
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex, mpsc};
+use std::thread;
+
+// `Box<FnOnce()>` cannot be called directly; this is the usual workaround
+// until `FnBox` is stable.
+trait FnBox {
+    fn call_box(self: Box<Self>);
+}
+
+impl<F: FnOnce()> FnBox for F {
+    fn call_box(self: Box<F>) {
+        (*self)()
+    }
+}
+
+type Job = Box<FnBox + Send + 'static>;
+
+/// Runs jobs on a fixed number of worker threads.
+///
+/// Dropping the pool waits until all submitted jobs, including those
+/// submitted through a `PoolHandle` while the pool is being dropped, have
+/// finished.
+pub struct ThreadPool {
+    workers: Vec<thread::JoinHandle<()>>,
+    handle: Option<PoolHandle>,
+}
+
+/// Submits jobs to a `ThreadPool`; can be moved into jobs to submit follow-up
+/// work.
+#[derive(Clone)]
+pub struct PoolHandle {
+    sender: mpsc::Sender<Job>,
+}
+
+impl ThreadPool {
+    pub fn new(size: usize) -> ThreadPool {
+        assert!(size > 0);
+
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        let workers = (0..size).map(|_| {
+            let receiver = receiver.clone();
+            thread::spawn(move || {
+                loop {
+                    let job = receiver.lock().unwrap().recv();
+                    match job {
+                        Ok(job) => {
+                            // keep the worker alive if a job panics
+                            let _ = panic::catch_unwind(AssertUnwindSafe(move || job.call_box()));
+                        },
+                        Err(_) => break
+                    }
+                }
+            })
+        }).collect();
+
+        ThreadPool { workers: workers, handle: Some(PoolHandle { sender: sender }) }
+    }
+
+    pub fn handle(&self) -> PoolHandle {
+        self.handle.as_ref().unwrap().clone()
+    }
+
+    pub fn execute<F>(&self, f: F) where F: FnOnce() + Send + 'static {
+        self.handle.as_ref().unwrap().execute(f);
+    }
+}
+
+impl PoolHandle {
+    pub fn execute<F>(&self, f: F) where F: FnOnce() + Send + 'static {
+        // workers only exit once all senders are gone, so this cannot fail
+        let _ = self.sender.send(Box::new(f));
+    }
+}
+
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        // workers stop once the queue is empty and no handle is left
+        self.handle = None;
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+/// A counting semaphore, used to bound how many large buffers (e.g. full
+/// resolution decodes) exist at the same time.
+pub struct Semaphore {
+    count: Mutex<usize>,
+    cvar: Condvar,
+}
+
+/// Released when dropped.
+pub struct Permit {
+    semaphore: Arc<Semaphore>,
+}
+
+impl Semaphore {
+    pub fn new(count: usize) -> Semaphore {
+        Semaphore { count: Mutex::new(count), cvar: Condvar::new() }
+    }
+
+    /// Blocks until a permit is available.
+    pub fn acquire(semaphore: &Arc<Semaphore>) -> Permit {
+        let mut count = semaphore.count.lock().unwrap();
+        while *count == 0 {
+            count = semaphore.cvar.wait(count).unwrap();
+        }
+        *count -= 1;
+        Permit { semaphore: semaphore.clone() }
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        *self.semaphore.count.lock().unwrap() += 1;
+        self.semaphore.cvar.notify_one();
+    }
+}
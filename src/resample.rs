@@ -0,0 +1,231 @@
+//! Separable resampling with a choice of filters, optionally in linear light.
+//!
+//! Resampling sRGB encoded values directly darkens fine, high contrast
+//! detail such as text and foliage. With `linear_light`, pixels are
+//! converted to linear light before and back to sRGB after resampling.
+
+use std::cmp;
+use std::collections::VecDeque;
+use std::f32::consts::PI;
+use std::str::FromStr;
+
+use image::{DynamicImage, GenericImage, ImageBuffer, ImageLuma8, ImageLumaA8, ImageRgb8, ImageRgba8};
+
+/// Reconstruction filter used for resizing.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ResizeFilter {
+    /// Averages the source pixels covered by each target pixel.
+    Box,
+    /// Mitchell-Netravali cubic (B = C = 1/3), balancing blur and ringing.
+    Mitchell,
+    /// Catmull-Rom cubic (B = 0, C = 1/2), sharper than Mitchell.
+    CatmullRom,
+    /// Three lobed Lanczos windowed sinc, the sharpest, with some ringing.
+    Lanczos3,
+}
+
+impl FromStr for ResizeFilter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ResizeFilter, String> {
+        match s {
+            "box" | "area" => Ok(ResizeFilter::Box),
+            "mitchell" => Ok(ResizeFilter::Mitchell),
+            "catmull-rom" => Ok(ResizeFilter::CatmullRom),
+            "lanczos3" => Ok(ResizeFilter::Lanczos3),
+            _ => Err(format!("unknown resize filter '{}'", s))
+        }
+    }
+}
+
+// Cubic filter of Mitchell and Netravali with parameters `b` and `c`.
+fn cubic(x: f32, b: f32, c: f32) -> f32 {
+    let x = x.abs();
+    let (x2, x3) = (x * x, x * x * x);
+    if x < 1.0 {
+        ((12.0 - 9.0 * b - 6.0 * c) * x3 + (-18.0 + 12.0 * b + 6.0 * c) * x2 + (6.0 - 2.0 * b)) / 6.0
+    } else if x < 2.0 {
+        ((-b - 6.0 * c) * x3 + (6.0 * b + 30.0 * c) * x2 + (-12.0 * b - 48.0 * c) * x + (8.0 * b + 24.0 * c)) / 6.0
+    } else {
+        0.0
+    }
+}
+
+fn sinc(x: f32) -> f32 {
+    if x == 0.0 {
+        1.0
+    } else {
+        let x = x * PI;
+        x.sin() / x
+    }
+}
+
+impl ResizeFilter {
+    // Radius of the filter, in source pixels when upscaling.
+    fn support(&self) -> f32 {
+        match *self {
+            ResizeFilter::Box => 0.5,
+            ResizeFilter::Mitchell | ResizeFilter::CatmullRom => 2.0,
+            ResizeFilter::Lanczos3 => 3.0,
+        }
+    }
+
+    fn kernel(&self, x: f32) -> f32 {
+        match *self {
+            ResizeFilter::Box => if x >= -0.5 && x < 0.5 { 1.0 } else { 0.0 },
+            ResizeFilter::Mitchell => cubic(x, 1.0 / 3.0, 1.0 / 3.0),
+            ResizeFilter::CatmullRom => cubic(x, 0.0, 0.5),
+            ResizeFilter::Lanczos3 => if x.abs() < 3.0 { sinc(x) * sinc(x / 3.0) } else { 0.0 },
+        }
+    }
+}
+
+fn srgb_to_linear(v: f32) -> f32 {
+    if v <= 0.04045 { v / 12.92 } else { ((v + 0.055) / 1.055).powf(2.4) }
+}
+
+fn linear_to_srgb(v: f32) -> f32 {
+    if v <= 0.0031308 { v * 12.92 } else { 1.055 * v.powf(1.0 / 2.4) - 0.055 }
+}
+
+// For each target pixel along an axis of `src_len` pixels resized to
+// `dst_len`: the first source pixel contributing to it, and the normalized
+// weights of the contributing pixels.
+fn weights(src_len: u32, dst_len: u32, filter: ResizeFilter) -> Vec<(usize, Vec<f32>)> {
+    let scale = src_len as f32 / dst_len as f32;
+    // when downscaling, the filter is stretched to cover all source pixels
+    let filter_scale = scale.max(1.0);
+    let support = filter.support() * filter_scale;
+
+    (0..dst_len).map(|i| {
+        let center = (i as f32 + 0.5) * scale;
+        let start = (center - support).floor().max(0.0) as usize;
+        let end = cmp::min((center + support).ceil() as usize, src_len as usize);
+
+        let mut ws = (start..end)
+            .map(|j| filter.kernel((j as f32 + 0.5 - center) / filter_scale))
+            .collect::<Vec<_>>();
+        let sum = ws.iter().fold(0.0, |sum, w| sum + w);
+        if sum == 0.0 {
+            // only possible for degenerate sizes; use the nearest pixel
+            let nearest = cmp::min(center as usize, src_len as usize - 1);
+            return (nearest, vec![1.0]);
+        }
+        for w in ws.iter_mut() {
+            *w /= sum;
+        }
+        (start, ws)
+    }).collect()
+}
+
+// The 8 bit samples of `img` and the number of samples per pixel.
+fn samples(img: &DynamicImage) -> (&[u8], usize) {
+    match *img {
+        ImageLuma8(ref buf) => (&**buf, 1),
+        ImageLumaA8(ref buf) => (&**buf, 2),
+        ImageRgb8(ref buf) => (&**buf, 3),
+        ImageRgba8(ref buf) => (&**buf, 4),
+    }
+}
+
+// Added to the alpha of every pixel when weighting colors, so that fully
+// transparent areas keep their colors instead of turning black. As the
+// filter weights sum up to 1, it is simply subtracted from the result.
+const TRANSPARENT_WEIGHT: f32 = 1.0 / 1024.0;
+
+// The pixel `px` (gray, gray and alpha, RGB or RGBA samples) as RGBA floats,
+// decoded with the lookup table `decode` and premultiplied with alpha plus
+// `TRANSPARENT_WEIGHT`, which is also what the alpha channel holds.
+fn premultiplied(px: &[u8], decode: &[f32]) -> [f32; 4] {
+    let (r, g, b, a) = match px.len() {
+        1 => (px[0], px[0], px[0], 255),
+        2 => (px[0], px[0], px[0], px[1]),
+        3 => (px[0], px[1], px[2], 255),
+        _ => (px[0], px[1], px[2], px[3]),
+    };
+    let alpha = a as f32 / 255.0 + TRANSPARENT_WEIGHT;
+    [decode[r as usize] * alpha, decode[g as usize] * alpha, decode[b as usize] * alpha, alpha]
+}
+
+// Resamples the source row `row` horizontally into `out`, `4 * width`
+// premultiplied RGBA floats.
+fn resample_row(row: &[u8], channels: usize, horizontal: &[(usize, Vec<f32>)], decode: &[f32], out: &mut [f32]) {
+    for (x, &(start, ref ws)) in horizontal.iter().enumerate() {
+        let mut sum = [0.0f32; 4];
+        for (k, w) in ws.iter().enumerate() {
+            let i = (start + k) * channels;
+            let px = premultiplied(&row[i..i + channels], decode);
+            for c in 0..4 {
+                sum[c] += w * px[c];
+            }
+        }
+        out[x * 4..(x + 1) * 4].copy_from_slice(&sum);
+    }
+}
+
+/// Resizes `img` to `width` x `height` with `filter`, in linear light if
+/// `linear_light` is set. Colors are weighted by their alpha.
+pub fn resize(img: &DynamicImage, width: u32, height: u32, filter: ResizeFilter, linear_light: bool) -> DynamicImage {
+    let (src, channels) = samples(img);
+    let (src_w, src_h) = img.dimensions();
+    let (width, height) = (cmp::max(width, 1), cmp::max(height, 1));
+
+    let decode = (0..256).map(|v| {
+        let v = v as f32 / 255.0;
+        if linear_light { srgb_to_linear(v) } else { v }
+    }).collect::<Vec<_>>();
+
+    let horizontal = weights(src_w, width, filter);
+    let vertical = weights(src_h, height, filter);
+    let (src_w, width) = (src_w as usize, width as usize);
+    let src_row = |y: usize| &src[y * src_w * channels..(y + 1) * src_w * channels];
+
+    // Only the horizontally resampled rows covered by the vertical filter
+    // are kept. Its window only moves down, so rows are resampled once and
+    // dropped once passed.
+    let mut window = VecDeque::<Vec<f32>>::new();
+    // source row of `window[0]`
+    let mut first = 0;
+
+    let mut out_row = vec![0.0f32; width * 4];
+    let mut data = Vec::with_capacity(width * height as usize * 4);
+    for &(start, ref ws) in vertical.iter() {
+        if start < first {
+            window.clear();
+        }
+        while first < start && !window.is_empty() {
+            window.pop_front();
+            first += 1;
+        }
+        if window.is_empty() {
+            first = start;
+        }
+        while window.len() < start + ws.len() - first {
+            let mut row = vec![0.0f32; width * 4];
+            resample_row(src_row(first + window.len()), channels, &horizontal, &decode, &mut row);
+            window.push_back(row);
+        }
+
+        for o in out_row.iter_mut() {
+            *o = 0.0;
+        }
+        for (k, w) in ws.iter().enumerate() {
+            for (o, v) in out_row.iter_mut().zip(window[start - first + k].iter()) {
+                *o += w * v;
+            }
+        }
+
+        for px in out_row.chunks(4) {
+            let weight = px[3].min(1.0 + TRANSPARENT_WEIGHT);
+            let alpha = (weight - TRANSPARENT_WEIGHT).max(0.0);
+            for c in 0..3 {
+                let v = if weight > 0.0 { (px[c] / weight).max(0.0).min(1.0) } else { 0.0 };
+                let v = if linear_light { linear_to_srgb(v) } else { v };
+                data.push((v * 255.0 + 0.5) as u8);
+            }
+            data.push((alpha * 255.0 + 0.5) as u8);
+        }
+    }
+
+    ImageRgba8(ImageBuffer::from_raw(width as u32, height, data).unwrap())
+}
@@ -0,0 +1,36 @@
+//! Unsharp masking, to restore crispness lost when downscaling.
+
+use image::{imageops, DynamicImage, ImageRgba8};
+
+/// Settings of an unsharp mask: the difference between the image and a
+/// blurred copy of it is amplified and added back.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct UnsharpMask {
+    /// Standard deviation of the Gaussian blur, in pixels of the thumbnail.
+    pub radius: f32,
+    /// Strength; 1.0 adds the difference once (100%).
+    pub amount: f32,
+    /// Differences (0-255) below this are left alone, so that smooth areas
+    /// and noise are not sharpened.
+    pub threshold: u8,
+}
+
+impl UnsharpMask {
+    /// Returns a sharpened copy of `img`; alpha is left as is.
+    pub fn apply(&self, img: &DynamicImage) -> DynamicImage {
+        let mut sharpened = img.to_rgba();
+        let blurred = imageops::blur(&sharpened, self.radius);
+
+        for (px, blurred_px) in sharpened.pixels_mut().zip(blurred.pixels()) {
+            for c in 0..3 {
+                let diff = px.data[c] as f32 - blurred_px.data[c] as f32;
+                if diff.abs() < self.threshold as f32 {
+                    continue;
+                }
+                let value = px.data[c] as f32 + self.amount * diff;
+                px.data[c] = value.max(0.0).min(255.0).round() as u8;
+            }
+        }
+        ImageRgba8(sharpened)
+    }
+}
@@ -1,38 +1,239 @@
-use image;
-use image::{GenericImage, DynamicImage, ImageRgba8};
+use image::{GenericImage, DynamicImage};
 
-use rexiv2;
 use rexiv2::Orientation;
 
-use std::path::Path;
-use std::cmp::Ordering;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::cmp;
+use std::str::FromStr;
 
-use jpegimpex::{read_jpeg, write_jpeg};
+use config::ThumbnailSpec;
+use error::Error;
+use decode::{decode_image, image_format, read_preview, ImageHeader};
+use format::FileFormat;
+use resample;
+use encode::{encode_image, write_image};
 
-fn write_resized_image (
-    img: &DynamicImage,
-    w: u32,
-    h: u32,
-    quality: u8,
-    output_filename: &Path
-) {
-    let thumb = image::imageops::resize(img, w, h, image::CatmullRom);
+pub use metadata::{OutputFormat, ThumbnailInfo};
 
-    let out:DynamicImage = ImageRgba8(thumb);
-    
-    write_jpeg (&out, output_filename, quality);
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<OutputFormat, String> {
+        match s {
+            "jpeg" | "jpg" => Ok(OutputFormat::Jpeg),
+            "webp" => Ok(OutputFormat::WebP),
+            "webp-lossless" => Ok(OutputFormat::WebPLossless),
+            // fail when loading the configuration, not on the first image
+            "avif" if cfg!(feature = "avif") => Ok(OutputFormat::Avif),
+            "avif" => Err("AVIF output needs thumbrs to be built with the `avif` feature".to_string()),
+            "png" => Ok(OutputFormat::Png),
+            _ => Err(format!("unknown output format '{}'", s))
+        }
+    }
+}
+
+/// How the requested size of a thumbnail is applied to the source image.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FitMode {
+    /// The longest side of the thumbnail is the requested size.
+    LongestSide,
+    /// The width of the thumbnail is the requested size.
+    Width,
+    /// The height of the thumbnail is the requested size.
+    Height,
+    /// The thumbnail is as large as possible within the given box.
+    Contain(u32, u32),
+    /// The thumbnail fills the given box, cropping the center of the image.
+    Cover(u32, u32),
+}
+
+impl FromStr for FitMode {
+    type Err = String;
+
+    /// Parses the modes without a box, see `config` for the others.
+    fn from_str(s: &str) -> Result<FitMode, String> {
+        match s {
+            "longest-side" => Ok(FitMode::LongestSide),
+            "width" => Ok(FitMode::Width),
+            "height" => Ok(FitMode::Height),
+            _ => Err(format!("unknown fit mode '{}'", s))
+        }
+    }
+}
+
+/// What is done with thumbnails that would be larger than the source image.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UpscalePolicy {
+    /// Enlarge the image anyway.
+    Upscale,
+    /// Do not make the thumbnail.
+    Skip,
+    /// Make the thumbnail at the size of the source image.
+    Clamp,
+    /// Copy the original file if it is in the format of the thumbnail and
+    /// needs no rotation; otherwise re-encode it at full size.
+    Copy,
+}
+
+impl FromStr for UpscalePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<UpscalePolicy, String> {
+        match s {
+            "upscale" => Ok(UpscalePolicy::Upscale),
+            "skip" => Ok(UpscalePolicy::Skip),
+            "clamp" => Ok(UpscalePolicy::Clamp),
+            "copy" => Ok(UpscalePolicy::Copy),
+            _ => Err(format!("unknown upscale policy '{}'", s))
+        }
+    }
+}
+
+// `x * num / den`, rounded to the nearest integer but at least 1.
+fn scale_dimension(x: u32, num: u32, den: u32) -> u32 {
+    let (x, num, den) = (x as u64, num as u64, den as u64);
+    cmp::max(1, ((2 * x * num + den) / (2 * den)) as u32)
+}
+
+impl FitMode {
+    /// Dimensions an image of `width` x `height` is resized to for a
+    /// thumbnail of `size`. For `Cover`, the result is then cropped to the
+    /// box.
+    pub fn resize_dimensions(&self, size: u32, width: u32, height: u32) -> (u32, u32) {
+        let (width, height) = (cmp::max(1, width), cmp::max(1, height));
+        // compare aspect ratios exactly, `a / b >= c / d` <=> `a * d >= c * b`
+        let wider_than = |box_w: u32, box_h: u32| width as u64 * box_h as u64 >= box_w as u64 * height as u64;
+
+        let by_width = |w: u32| (w, scale_dimension(height, w, width));
+        let by_height = |h: u32| (scale_dimension(width, h, height), h);
+
+        match *self {
+            FitMode::LongestSide if width >= height => by_width(size),
+            FitMode::LongestSide => by_height(size),
+            FitMode::Width => by_width(size),
+            FitMode::Height => by_height(size),
+            FitMode::Contain(w, h) if wider_than(w, h) => by_width(w),
+            FitMode::Contain(_, h) => by_height(h),
+            FitMode::Cover(w, h) if wider_than(w, h) => by_height(h),
+            FitMode::Cover(w, _) => by_width(w),
+        }
+    }
+}
+
+fn resize (img: &DynamicImage, w: u32, h: u32, spec: &ThumbnailSpec) -> DynamicImage {
+    // e.g. thumbnails clamped to the size of the source
+    if img.dimensions() == (w, h) {
+        return img.clone();
+    }
+    resample::resize(img, w, h, spec.filter, spec.linear_light)
+}
+
+/// A decoded image together with the EXIF transform that is needed to
+/// display it. Thumbnails are made by resizing the image first and
+/// transforming it afterwards, which is a lot cheaper than rotating the full
+/// resolution image.
+pub struct SourceImage {
+    /// The image as stored in the file.
+    pub img: DynamicImage,
+    pub orientation: Option<Orientation>,
+    /// The file `img` was decoded from, if it is the full image rather than
+    /// a preview; used by `UpscalePolicy::Copy`.
+    pub original: Option<(PathBuf, FileFormat)>,
+}
+
+impl SourceImage {
+    /// Dimensions of the image as displayed.
+    pub fn dimensions(&self) -> (u32, u32) {
+        match swaps_dimensions(self.orientation) {
+            true => (self.img.height(), self.img.width()),
+            false => self.img.dimensions()
+        }
+    }
+
+    /// The image as displayed.
+    pub fn into_rotated(self) -> DynamicImage {
+        rotate(self.img, self.orientation)
+    }
+}
+
+/// An image that is already displayed as stored.
+impl From<DynamicImage> for SourceImage {
+    fn from(img: DynamicImage) -> SourceImage {
+        SourceImage { img: img, orientation: None, original: None }
+    }
 }
 
 pub fn read_and_rotate (
     img_filename: &Path
-) -> DynamicImage {
-    let exif_orientation = rexiv2::Metadata::new_from_path(img_filename.to_str().unwrap())
-        .map(|e| e.get_orientation());
+) -> Result<DynamicImage, Error> {
+    let header = try!(ImageHeader::read(img_filename, try!(image_format(img_filename))));
+    read_source_impl(&header, None).map(SourceImage::into_rotated)
+}
+
+/// Like `read_and_rotate`, but decodes at a reduced scale as long as the
+/// longest side of the image stays at least `longest_side` pixels.
+pub fn read_and_rotate_scaled (
+    img_filename: &Path,
+    longest_side: u32
+) -> Result<DynamicImage, Error> {
+    let header = try!(ImageHeader::read(img_filename, try!(image_format(img_filename))));
+    read_source_impl(&header, Some(&|w, h| FitMode::LongestSide.resize_dimensions(longest_side, w, h)))
+        .map(SourceImage::into_rotated)
+}
+
+/// Reads the image of `header` for making the thumbnails `specs`, at the
+/// smallest scale from which all of them can still be made. The EXIF
+/// orientation is applied to each thumbnail instead of the decoded image.
+pub fn read_source (
+    header: &ImageHeader,
+    specs: &[ThumbnailSpec]
+) -> Result<SourceImage, Error> {
+    read_source_impl(header, Some(&|w, h| min_source_size(specs, w, h)))
+}
+
+// Smallest size an image of `width` x `height` can be scaled to and still
+// serve all thumbnails `specs`.
+fn min_source_size (specs: &[ThumbnailSpec], width: u32, height: u32) -> (u32, u32) {
+    specs.iter()
+        .map(|spec| spec.fit.resize_dimensions(spec.size, width, height))
+        .fold((0, 0), |(w, h), (rw, rh)| (cmp::max(w, rw), cmp::max(h, rh)))
+}
+
+// Whether the image has to be rotated by 90 degrees for display.
+fn swaps_dimensions (exif_orientation: Option<Orientation>) -> bool {
+    match exif_orientation {
+        Some(Orientation::Rotate90HorizontalFlip) | Some(Orientation::Rotate90) |
+        Some(Orientation::Rotate90VerticalFlip) | Some(Orientation::Rotate270) => true,
+        _ => false
+    }
+}
+
+// Maps the dimensions `(w, h)` of the displayed image to the stored one, or
+// back.
+fn swap_if (swap: bool, (w, h): (u32, u32)) -> (u32, u32) {
+    match swap {
+        true => (h, w),
+        false => (w, h)
+    }
+}
 
-    let mut img = read_jpeg(img_filename);
+// Computes the minimum size of the stored image from `min_size`, which maps
+// the size of the displayed image to its minimum size.
+fn min_stored_size (
+    stored_size: (u32, u32),
+    exif_orientation: Option<Orientation>,
+    min_size: &Fn(u32, u32) -> (u32, u32)
+) -> (u32, u32) {
+    let swap = swaps_dimensions(exif_orientation);
+    let (w, h) = swap_if(swap, stored_size);
+    swap_if(swap, min_size(w, h))
+}
 
+fn rotate (mut img: DynamicImage, exif_orientation: Option<Orientation>) -> DynamicImage {
     match exif_orientation {
-        Ok(e) => match e {
+        Some(e) => match e {
             Orientation::Unspecified => (),
             Orientation::Normal => (),
             Orientation::HorizontalFlip => {
@@ -57,30 +258,332 @@ pub fn read_and_rotate (
                 img = img.rotate270();
             }
         },
-        Err(_) => ()
+        None => ()
     };
-    
+
     img
 }
 
+fn read_source_impl (
+    header: &ImageHeader,
+    min_size: Option<&Fn(u32, u32) -> (u32, u32)>
+) -> Result<SourceImage, Error> {
+    let orientation = header.orientation();
+    let min_size = min_size.map(|min_size| min_stored_size(header.size, orientation, min_size));
+    let img = try!(decode_image(header, min_size));
+
+    Ok(SourceImage { img: img, orientation: orientation, original: Some((header.path.clone(), header.format)) })
+}
+
+/// Like `read_source`, but reads the smallest preview image embedded in the
+/// file (e.g. the EXIF thumbnail) from which all thumbnails `specs` can be
+/// made, and that has the aspect ratio of the full image. This is a lot
+/// faster than decoding the full image; `Ok(None)` if there is no such
+/// preview.
+pub fn read_source_preview (
+    header: &ImageHeader,
+    specs: &[ThumbnailSpec]
+) -> Result<Option<SourceImage>, Error> {
+    let orientation = header.orientation();
+    let (min_w, min_h) = min_stored_size(header.size, orientation, &|w, h| min_source_size(specs, w, h));
+
+    let preview = try!(read_preview(header, min_w, min_h));
+    Ok(preview.map(|img| SourceImage { img: img, orientation: orientation, original: None }))
+}
+
+// Whether the thumbnail `spec` of `source` would be larger than `source`.
+fn exceeds_source (source: &SourceImage, spec: &ThumbnailSpec) -> bool {
+    let (width, height) = source.dimensions();
+    let (w, h) = spec.fit.resize_dimensions(spec.size, width, height);
+    w > width || h > height
+}
+
+// Dimensions, as stored, that the image is resized to for the thumbnail
+// `spec` of `source`; only larger than `source` for `UpscalePolicy::Upscale`.
+fn stored_resize_dimensions (source: &SourceImage, spec: &ThumbnailSpec) -> (u32, u32) {
+    let (width, height) = source.dimensions();
+    let resized = match exceeds_source(source, spec) && spec.upscale != UpscalePolicy::Upscale {
+        true => (width, height),
+        false => spec.fit.resize_dimensions(spec.size, width, height)
+    };
+    swap_if(swaps_dimensions(source.orientation), resized)
+}
+
+/// Dimensions of the thumbnail `spec` of `source`, as displayed; `None` if
+/// it is skipped for being larger than `source`. Thumbnails clamped to or
+/// copied from `source` can have the same dimensions for several `spec`s.
+pub fn thumbnail_dimensions (source: &SourceImage, spec: &ThumbnailSpec) -> Option<(u32, u32)> {
+    if exceeds_source(source, spec) {
+        match spec.upscale {
+            UpscalePolicy::Skip => return None,
+            UpscalePolicy::Copy => return Some(source.dimensions()),
+            UpscalePolicy::Upscale | UpscalePolicy::Clamp => ()
+        }
+    }
+    let stored = stored_resize_dimensions(source, spec);
+    let (w, h) = swap_if(swaps_dimensions(source.orientation), stored);
+    Some(match spec.fit {
+        FitMode::Cover(box_w, box_h) => (cmp::min(w, box_w), cmp::min(h, box_h)),
+        _ => (w, h)
+    })
+}
+
+// Whether the original file can be used as thumbnail in `format` as is.
+fn is_output_format (format: FileFormat, output: OutputFormat) -> bool {
+    match (format, output) {
+        (FileFormat::Jpeg, OutputFormat::Jpeg) |
+        (FileFormat::Png, OutputFormat::Png) |
+        (FileFormat::WebP, OutputFormat::WebP) => true,
+        _ => false
+    }
+}
+
+// The thumbnail `spec` of `source`, before encoding.
+enum Rendered<'a> {
+    /// Larger than `source`, and skipped.
+    Skipped,
+    /// The original file, to be used unchanged.
+    Original(&'a Path),
+    Image(DynamicImage),
+}
+
+// Turns the resized image into the thumbnail `spec`, applying the
+// orientation, the crop of `Cover` and sharpening.
+fn finish (resized: DynamicImage, orientation: Option<Orientation>, spec: &ThumbnailSpec) -> DynamicImage {
+    let mut thumb = rotate(resized, orientation);
+    let thumb = match spec.fit {
+        FitMode::Cover(box_w, box_h) => {
+            let (w, h) = thumb.dimensions();
+            let (box_w, box_h) = (cmp::min(w, box_w), cmp::min(h, box_h));
+            thumb.crop((w - box_w) / 2, (h - box_h) / 2, box_w, box_h)
+        },
+        _ => thumb
+    };
+    match spec.sharpen {
+        Some(ref mask) => mask.apply(&thumb),
+        None => thumb
+    }
+}
+
+// Makes the thumbnail `spec` of `source`, following its upscale policy.
+fn render<'a> (source: &'a SourceImage, spec: &ThumbnailSpec) -> Rendered<'a> {
+    if exceeds_source(source, spec) {
+        match spec.upscale {
+            UpscalePolicy::Skip => return Rendered::Skipped,
+            UpscalePolicy::Copy => {
+                let displayed_as_stored = match source.orientation {
+                    None | Some(Orientation::Unspecified) | Some(Orientation::Normal) => true,
+                    _ => false
+                };
+                if let Some((ref path, format)) = source.original {
+                    if displayed_as_stored && is_output_format(format, spec.format) {
+                        return Rendered::Original(path);
+                    }
+                }
+                return Rendered::Image(rotate(source.img.clone(), source.orientation));
+            },
+            UpscalePolicy::Upscale | UpscalePolicy::Clamp => ()
+        }
+    }
+    let (w, h) = stored_resize_dimensions(source, spec);
+    Rendered::Image(finish(resize(&source.img, w, h, spec), source.orientation, spec))
+}
+
+fn thumbnail_path (out_abspath: &Path, w: u32, h: u32, format: OutputFormat) -> Result<PathBuf, Error> {
+    let invalid_path = || Error::Path(out_abspath.to_path_buf());
+    let out_fname = try!(out_abspath.file_name().ok_or_else(&invalid_path));
+    let out_dir = try!(out_abspath.parent().ok_or_else(&invalid_path));
+
+    // the file name need not be unicode
+    let mut thumb_file = out_fname.to_os_string();
+    thumb_file.push(format!("_{}x{}.{}", w, h, format.extension()));
+    Ok(out_dir.join(thumb_file))
+}
+
+// Writes the finished thumbnail `thumb` next to `out_abspath`.
+fn write_thumbnail (thumb: &DynamicImage, spec: &ThumbnailSpec, out_abspath: &Path) -> Result<ThumbnailInfo, Error> {
+    let (w,h) = thumb.dimensions();
+    let thumb_path = try!(thumbnail_path(out_abspath, w, h, spec.format));
+
+    let quality = try!(write_image (thumb, &thumb_path, spec));
+
+    Ok(ThumbnailInfo { width: w, height: h, format: spec.format, quality: quality })
+}
+
+// Copies the original file of `source` next to `out_abspath`. Its quality
+// is not known.
+fn copy_original (original: &Path, source: &SourceImage, spec: &ThumbnailSpec, out_abspath: &Path) -> Result<ThumbnailInfo, Error> {
+    let (w,h) = source.dimensions();
+    let thumb_path = try!(thumbnail_path(out_abspath, w, h, spec.format));
+    try!(fs::copy(original, &thumb_path));
+
+    Ok(ThumbnailInfo { width: w, height: h, format: spec.format, quality: None })
+}
+
+/// Writes the thumbnail `spec` of `source` next to `out_abspath`. `Ok(None)`
+/// if it is skipped for being larger than `source`, see `UpscalePolicy`.
 pub fn make_thumbnail (
-    img: &DynamicImage,
-    size: u32,
-    quality: u8,
-    out_abspath: &Path) -> (u32, u32) {
-
-    let aspect = (img.width() as f64) / (img.height() as f64);
-    let s = size.clone() as f64;
-    let (w,h) = match img.width().cmp(&img.height()) {
-        Ordering::Greater => (size.clone(), (1.0/aspect * s) as u32),
-        Ordering::Less => ((aspect * s ) as u32, size.clone()),
-        Ordering::Equal => (size.clone(), size.clone())
+    source: &SourceImage,
+    spec: &ThumbnailSpec,
+    out_abspath: &Path) -> Result<Option<ThumbnailInfo>, Error> {
+
+    match render(source, spec) {
+        Rendered::Skipped => Ok(None),
+        Rendered::Original(original) => copy_original(original, source, spec, out_abspath).map(Some),
+        Rendered::Image(thumb) => write_thumbnail(&thumb, spec, out_abspath).map(Some)
+    }
+}
+
+/// Like `make_thumbnail`, but returns the encoded thumbnail instead of
+/// writing it to disk.
+pub fn encode_thumbnail (
+    source: &SourceImage,
+    spec: &ThumbnailSpec) -> Result<Option<(ThumbnailInfo, Vec<u8>)>, Error> {
+
+    let (w, h, quality, data) = match render(source, spec) {
+        Rendered::Skipped => return Ok(None),
+        Rendered::Original(original) => {
+            let mut data = Vec::new();
+            try!(File::open(original).and_then(|mut f| f.read_to_end(&mut data)));
+            let (w, h) = source.dimensions();
+            (w, h, None, data)
+        },
+        Rendered::Image(thumb) => {
+            let (w,h) = thumb.dimensions();
+            let (quality, data) = try!(encode_image (&thumb, spec));
+            (w, h, quality, data)
+        }
     };
 
-    let thumb_file = format!("{}_{}x{}.jpg", out_abspath.file_name().unwrap().to_str().unwrap(), w, h);
-    let thumb_path = out_abspath.parent().unwrap().join(Path::new(&thumb_file));
+    Ok(Some((ThumbnailInfo { width: w, height: h, format: spec.format, quality: quality }, data)))
+}
+
+/// Writes thumbnails for all `specs`, deriving smaller thumbnails from
+/// larger ones instead of always resizing `source`.
+///
+/// A thumbnail is only used as source if it is at least `min_ratio` times
+/// the size the next one is resized to; larger ratios trade speed for
+/// quality. Cropped (`Cover`) thumbnails are never used as source. Results
+/// are returned in the order of `specs`, see `make_thumbnail`.
+pub fn make_thumbnails_cascaded (
+    source: &SourceImage,
+    specs: &[ThumbnailSpec],
+    min_ratio: f64,
+    out_abspath: &Path) -> Vec<Result<Option<ThumbnailInfo>, Error>> {
+
+    // dimensions are always computed from the original, so that they
+    // do not depend on rounding in intermediate thumbnails
+    let resized = specs.iter()
+        .map(|spec| stored_resize_dimensions(source, spec))
+        .collect::<Vec<_>>();
+
+    let mut order = (0..specs.len()).collect::<Vec<_>>();
+    order.sort_by(|&a, &b| resized[b].0.cmp(&resized[a].0));
+
+    let mut results = (0..specs.len()).map(|_| None).collect::<Vec<_>>();
+    // resized (but not yet rotated) images, from largest to smallest
+    let mut generated = Vec::<DynamicImage>::new();
+
+    for i in order {
+        let spec = &specs[i];
+        // skipped and copied thumbnails need no resizing
+        match spec.upscale {
+            UpscalePolicy::Skip | UpscalePolicy::Copy if exceeds_source(source, spec) => {
+                results[i] = Some(make_thumbnail(source, spec, out_abspath));
+                continue;
+            },
+            _ => ()
+        }
+
+        let (w, h) = resized[i];
+        let (min_w, min_h) = ((w as f64 * min_ratio).ceil() as u32, (h as f64 * min_ratio).ceil() as u32);
+        let small = {
+            let from = generated.iter().rev()
+                .find(|t| t.width() >= min_w && t.height() >= min_h)
+                .unwrap_or(&source.img);
+            resize(from, w, h, spec)
+        };
+
+        let thumb = match spec.fit {
+            FitMode::Cover(..) => finish(small, source.orientation, spec),
+            _ => {
+                let thumb = finish(small.clone(), source.orientation, spec);
+                generated.push(small);
+                thumb
+            }
+        };
+        results[i] = Some(write_thumbnail(&thumb, spec, out_abspath).map(Some));
+    }
+
+    results.into_iter().map(|r| r.unwrap()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scale_dimension_rounds_to_nearest() {
+        assert_eq!(scale_dimension(3000, 150, 4000), 113);
+        assert_eq!(scale_dimension(3, 1, 2), 2);
+        assert_eq!(scale_dimension(667, 100, 1001), 67);
+        assert_eq!(scale_dimension(4000, 200, 3000), 267);
+    }
+
+    #[test]
+    fn scale_dimension_is_at_least_one() {
+        assert_eq!(scale_dimension(1, 1, 3), 1);
+        assert_eq!(scale_dimension(10, 150, 100000), 1);
+        assert_eq!(scale_dimension(0, 150, 100), 1);
+    }
+
+    #[test]
+    fn longest_side_of_landscape_and_portrait() {
+        assert_eq!(FitMode::LongestSide.resize_dimensions(150, 4000, 3000), (150, 113));
+        assert_eq!(FitMode::LongestSide.resize_dimensions(150, 3000, 4000), (113, 150));
+        assert_eq!(FitMode::LongestSide.resize_dimensions(150, 500, 500), (150, 150));
+    }
+
+    #[test]
+    fn width_and_height_fix_one_side() {
+        assert_eq!(FitMode::Width.resize_dimensions(200, 3000, 4000), (200, 267));
+        assert_eq!(FitMode::Width.resize_dimensions(200, 4000, 3000), (200, 150));
+        assert_eq!(FitMode::Height.resize_dimensions(200, 4000, 3000), (267, 200));
+        assert_eq!(FitMode::Height.resize_dimensions(200, 3000, 4000), (150, 200));
+    }
+
+    #[test]
+    fn contain_fits_into_box() {
+        let fit = FitMode::Contain(200, 100);
+        assert_eq!(fit.resize_dimensions(0, 4000, 3000), (133, 100));
+        assert_eq!(fit.resize_dimensions(0, 6000, 2000), (200, 67));
+        assert_eq!(fit.resize_dimensions(0, 3000, 4000), (75, 100));
+        // exactly the aspect ratio of the box
+        assert_eq!(fit.resize_dimensions(0, 4000, 2000), (200, 100));
+    }
+
+    #[test]
+    fn cover_fills_box() {
+        let fit = FitMode::Cover(150, 150);
+        assert_eq!(fit.resize_dimensions(150, 4000, 3000), (200, 150));
+        assert_eq!(fit.resize_dimensions(150, 3000, 4000), (150, 200));
+        assert_eq!(FitMode::Cover(200, 100).resize_dimensions(0, 6000, 2000), (300, 100));
+        assert_eq!(FitMode::Cover(200, 100).resize_dimensions(0, 3000, 4000), (200, 267));
+    }
 
-    write_resized_image (&img, w, h, quality, &thumb_path);
+    #[test]
+    fn odd_aspect_ratios() {
+        assert_eq!(FitMode::Width.resize_dimensions(100, 1001, 667), (100, 67));
+        assert_eq!(FitMode::LongestSide.resize_dimensions(150, 10000, 1000), (150, 15));
+        assert_eq!(FitMode::LongestSide.resize_dimensions(150, 1000, 10000), (15, 150));
+    }
 
-    (w,h)
+    #[test]
+    fn extreme_aspect_ratios_give_one_pixel() {
+        assert_eq!(FitMode::LongestSide.resize_dimensions(150, 100000, 10), (150, 1));
+        assert_eq!(FitMode::Height.resize_dimensions(100, 1, 5000), (1, 100));
+        assert_eq!(FitMode::Contain(100, 100).resize_dimensions(0, 1, 5000), (1, 100));
+        // degenerate images are treated as 1 pixel wide or high
+        assert_eq!(FitMode::LongestSide.resize_dimensions(150, 0, 0), (150, 150));
+    }
 }